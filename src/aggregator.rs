@@ -0,0 +1,884 @@
+use std::io::Read;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(debug_assertions)]
+use std::sync::Mutex;
+use std::thread::ScopedJoinHandle;
+use std::time::Instant;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::chunk::Segments;
+use crate::decode::{self, Compression};
+use crate::scan::Delimiters;
+use crate::sensor::{parse_temp, Tracking};
+use crate::stream::process_stream;
+use crate::table::{Key, StationTable};
+use crate::{Chunk, Format, OnError, ParseError, ParseErrorKind, Reject, Rejects, Sensor, Symbol};
+
+pub(crate) type HashMap = ahash::AHashMap<Symbol, Sensor>;
+
+/// Default size of the chunks, small enough for the workers to even out their load and large
+/// enough to make the per-chunk overhead negligible
+const CHUNK_SIZE: usize = 8 << 20;
+
+/// Serializes as a map from station name to sensor, in name order
+impl serde::Serialize for Results {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_map(self.iter())
+    }
+}
+
+/// Multi-threaded aggregation of `<station>;<temperature>` measurements
+///
+/// ```no_run
+/// let data = std::fs::read("measurements.txt")?;
+/// let results = chunkit::Aggregator::new().threads(4).run(&data)?;
+/// for (name, sensor) in results.iter() {
+///     println!("{name}: {:.1}/{:.1}/{:.1}", sensor.min(), sensor.mean(), sensor.max());
+/// }
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct Aggregator {
+    threads: usize,
+    chunks: Option<usize>,
+    chunk_size: usize,
+    format: Format,
+    on_error: OnError,
+    tracking: Tracking,
+    verbose: bool,
+}
+
+impl Aggregator {
+    /// Uses as many threads as there are logical cores, and chunks of 8 MiB
+    pub fn new() -> Self {
+        Self {
+            threads: num_cpus::get(),
+            chunks: None,
+            chunk_size: CHUNK_SIZE,
+            format: Format::new(),
+            on_error: OnError::Fail,
+            tracking: Tracking::default(),
+            verbose: false,
+        }
+    }
+
+    /// Sets the number of worker threads (at least 1)
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Sets the number of chunks the input is split into (at least 1), instead of splitting it
+    /// into chunks of `chunk_size` bytes
+    pub fn chunks(mut self, chunks: usize) -> Self {
+        self.chunks = Some(chunks.max(1));
+        self
+    }
+
+    /// Sets the nominal size of the chunks in bytes (at least 1), defaults to 8 MiB.
+    ///
+    /// The workers pull the chunks one at a time, so that a slow thread only delays the run by
+    /// about one chunk. The chunks are moved to the record boundaries as they are pulled.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self.chunks = None;
+        self
+    }
+
+    /// Sets the layout of the records, defaults to the 1BRC one
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets what to do with the records that cannot be parsed, defaults to [`OnError::Fail`]
+    pub fn on_error(mut self, on_error: OnError) -> Self {
+        self.on_error = on_error;
+        self
+    }
+
+    /// Also tracks the variance, standard deviation, skewness and kurtosis of each station, see
+    /// [`Sensor::variance`]. Off by default as it slows the aggregation down.
+    pub fn moments(mut self, moments: bool) -> Self {
+        self.tracking.moments = moments;
+        self
+    }
+
+    /// Also keeps the number of readings of each temperature of each station, to get their exact
+    /// percentiles with [`Sensor::percentile`]. Off by default as it takes up to 8 KiB per station
+    /// and thread.
+    pub fn histograms(mut self, histograms: bool) -> Self {
+        self.tracking.histogram = histograms;
+        self
+    }
+
+    /// Also estimates the percentiles of each station with a sketch, see [`Sensor::percentile`].
+    /// The estimates are within a relative error of `accuracy`, clamped to [0.0001, 0.5], of the
+    /// exact percentiles. The readings are still one decimal temperatures, so the sketches are
+    /// for now only an alternative to the histograms, that takes less memory with a coarse
+    /// accuracy. Off by default.
+    ///
+    /// ```
+    /// let data = b"Abha;1.0\nAbha;2.0\nAbha;3.0\nAbha;60.0\n";
+    /// let results = chunkit::Aggregator::new().sketches(Some(0.01)).run(data)?;
+    /// let median = results.get("Abha").unwrap().median().unwrap();
+    /// assert!((median - 2.0).abs() <= 0.02);
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn sketches(mut self, accuracy: Option<f64>) -> Self {
+        self.tracking.sketch = accuracy;
+        self
+    }
+
+    /// Prints the chunking and per-thread timings on stderr
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Aggregates the measurements contained in `buf`
+    pub fn run(&self, buf: &[u8]) -> Result<Results> {
+        let mut results = self.run_all(&[buf])?;
+        Ok(results.pop().unwrap_or_default())
+    }
+
+    /// Aggregates the measurements of several inputs, returning the results of each one.
+    ///
+    /// The chunks of all the inputs are processed by the same pool of workers, so that small
+    /// inputs do not leave threads idle. A [`ParseError`] tells which input it comes from with
+    /// [`ParseError::file`], the first one of the first failing input is returned.
+    ///
+    /// ```
+    /// use chunkit::{Aggregator, ParseError, Results};
+    ///
+    /// let inputs = [&b"Abha;1.0\nBaku;2.0\n"[..], b"", b"Abha;-3.0\nCairo;4.0"];
+    /// let aggregator = Aggregator::new().threads(3).chunk_size(4);
+    /// let results = aggregator.run_all(&inputs)?;
+    /// assert_eq!(results.len(), 3);
+    /// assert_eq!(results[0].get("Abha").unwrap().count(), 1);
+    /// assert!(results[1].is_empty());
+    /// assert_eq!(results[2].get("Abha").unwrap().min(), -3.0);
+    ///
+    /// let mut merged = Results::default();
+    /// results.into_iter().for_each(|results| merged.merge(results));
+    /// assert_eq!(merged.get("Abha").unwrap().count(), 2);
+    /// assert_eq!(merged.len(), 3);
+    ///
+    /// let invalid = [&b"Abha;1.0\n"[..], b"Abha;1.0\nAbha;x\n", b"Abha\n"];
+    /// let err = aggregator.run_all(&invalid).unwrap_err().downcast::<ParseError>()?;
+    /// assert_eq!((err.file, err.offset, err.line), (1, 9, 2));
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn run_all<B: AsRef<[u8]>>(&self, bufs: &[B]) -> Result<Vec<Results>> {
+        let segments = bufs
+            .iter()
+            .enumerate()
+            .map(|(file, buf)| {
+                let buf = buf.as_ref();
+                let chunk_size = match self.chunks {
+                    Some(chunks) => (buf.len() - self.format.body_start(buf)).div_ceil(chunks),
+                    None => self.chunk_size,
+                };
+                Segments::new(buf, file, &self.format, chunk_size)
+            })
+            .collect::<Result<Vec<_>>>()
+            .context("unable to chunk the input")?;
+        if self.verbose {
+            let nb_chunks: usize = segments.iter().map(Segments::len).sum();
+            eprintln!("processing {nb_chunks} chunks...");
+        }
+
+        let start = Instant::now();
+        let sensors = process_chunks(
+            &segments,
+            &self.format,
+            self.on_error,
+            self.tracking,
+            self.threads,
+            self.verbose,
+        )
+        .map_err(|err| match err.downcast::<ParseError>() {
+            Ok(err) => {
+                let buf = bufs[err.file].as_ref();
+                err.locate(buf).into()
+            }
+            Err(err) => err,
+        })?;
+        if self.verbose {
+            eprintln!("processing time {:?}", start.elapsed());
+        }
+
+        let start = Instant::now();
+        let results = sensors.into_iter().map(merge_results).collect();
+        if self.verbose {
+            eprintln!("merge took {:?}", start.elapsed());
+        }
+
+        Ok(results)
+    }
+
+    /// Aggregates the measurements of the compressed input `buf`, see [`decode::decompress`]
+    pub fn run_compressed(&self, buf: &[u8], compression: Compression) -> Result<Results> {
+        self.run_reader(decode::decompress(buf, compression, self.threads)?)
+    }
+
+    /// Aggregates the measurements read from `reader`, eg. stdin or a pipe.
+    ///
+    /// The input is read in buffers of `chunk_size` bytes that the worker threads process as
+    /// they come, reusing a bounded number of them.
+    ///
+    /// ```
+    /// let data = b"\"New\nYork\";1.0\nAbha;2.0\nAbha;4.5";
+    /// let results = chunkit::Aggregator::new().chunk_size(4).run_reader(&data[..])?;
+    /// assert_eq!(results.get("New\nYork").unwrap().count(), 1);
+    /// assert_eq!(results.get("Abha").unwrap().max(), 4.5);
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn run_reader(&self, mut reader: impl Read) -> Result<Results> {
+        let start = Instant::now();
+        let results = process_stream(
+            &mut reader,
+            &self.format,
+            self.on_error,
+            self.tracking,
+            self.threads,
+            self.chunk_size,
+            self.verbose,
+        )?;
+        if self.verbose {
+            eprintln!("processing time {:?}", start.elapsed());
+        }
+        Ok(results)
+    }
+}
+
+impl Default for Aggregator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The aggregated sensors, sorted by station name
+#[derive(Clone, Debug, Default)]
+pub struct Results {
+    sensors: Vec<(Symbol, Sensor)>,
+    rejects: Rejects,
+}
+
+impl Results {
+    /// Returns the sensor of the given station, if any
+    pub fn get(&self, name: &str) -> Option<&Sensor> {
+        self.sensors
+            .binary_search_by(|(other, _)| other.as_str().cmp(name))
+            .ok()
+            .map(|index| &self.sensors[index].1)
+    }
+
+    /// Iterates over the stations in name order
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = (&str, &Sensor)> {
+        self.sensors
+            .iter()
+            .map(|(name, sensor)| (name.as_str(), sensor))
+    }
+
+    /// The number of distinct stations
+    pub fn len(&self) -> usize {
+        self.sensors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sensors.is_empty()
+    }
+
+    /// Whether the sensors track their moments, see [`Aggregator::moments`]
+    pub fn has_moments(&self) -> bool {
+        !self.is_empty()
+            && self
+                .sensors
+                .iter()
+                .all(|(_, sensor)| sensor.variance().is_some())
+    }
+
+    /// Whether the sensors keep their histogram, see [`Aggregator::histograms`]
+    pub fn has_histograms(&self) -> bool {
+        !self.is_empty()
+            && self
+                .sensors
+                .iter()
+                .all(|(_, sensor)| sensor.histogram().is_some())
+    }
+
+    /// Whether the sensors keep a sketch, see [`Aggregator::sketches`]
+    pub fn has_sketches(&self) -> bool {
+        !self.is_empty()
+            && self
+                .sensors
+                .iter()
+                .all(|(_, sensor)| sensor.sketch().is_some())
+    }
+
+    /// The records that were skipped, see [`Aggregator::on_error`]
+    pub fn rejects(&self) -> &Rejects {
+        &self.rejects
+    }
+
+    /// Merges the results of another input into these ones
+    pub fn merge(&mut self, other: Results) {
+        self.rejects.merge(other.rejects);
+        if self.sensors.is_empty() {
+            self.sensors = other.sensors;
+            return;
+        }
+        let mut all_sensors: HashMap = std::mem::take(&mut self.sensors).into_iter().collect();
+        for (name, s) in other.sensors {
+            all_sensors
+                .entry(name)
+                .and_modify(|sensor| sensor.merge(&s))
+                .or_insert(s);
+        }
+        self.sensors = all_sensors.into_iter().collect();
+        self.sensors.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+
+    pub fn into_vec(self) -> Vec<(Symbol, Sensor)> {
+        self.sensors
+    }
+
+    /// Sorts the sensors by station name and the rejects by offset
+    pub(crate) fn sorted(sensors: HashMap, mut rejects: Rejects) -> Self {
+        let mut sensors: Vec<_> = sensors.into_iter().collect();
+        sensors.sort_by(|(a, _), (b, _)| a.cmp(b));
+        rejects.sort();
+        Self { sensors, rejects }
+    }
+}
+
+/// Processes the chunks of all the `segments` on a pool of `nb_threads` workers, each pulling
+/// the index of its next chunk from a shared cursor until there are none left. The indices run
+/// through the inputs one after the other, and every worker aggregates all of its chunks of an
+/// input into the same table.
+///
+/// A failing worker stops the others from pulling more chunks. The chunks before the failing one
+/// have already been pulled and are processed to the end, so the error returned is the first one
+/// of the inputs.
+///
+/// Returns the tables of the workers for each input.
+fn process_chunks<'a>(
+    segments: &[Segments<'a>],
+    format: &Format,
+    on_error: OnError,
+    tracking: Tracking,
+    nb_threads: usize,
+    verbose: bool,
+) -> Result<Vec<Vec<(StationTable<'a>, Rejects)>>> {
+    // the index of the first chunk of each input, and their number
+    let firsts: Vec<usize> = segments
+        .iter()
+        .scan(0, |first, segments| {
+            let index = *first;
+            *first += segments.len();
+            Some(index)
+        })
+        .collect();
+    let total: usize = segments.iter().map(Segments::len).sum();
+
+    let cursor = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    // the chunks pulled from each input, checked to tile it once they are all processed
+    #[cfg(debug_assertions)]
+    let pulled = Mutex::new(vec![Vec::new(); segments.len()]);
+    let workers = std::thread::scope(|ctx| {
+        let handles = (0..nb_threads.min(total))
+            .map(|_| {
+                ctx.spawn(|| {
+                    let start = Instant::now();
+                    let tid = std::thread::current().id();
+                    let mut tables: Vec<Option<(StationTable<'a>, Rejects)>> =
+                        segments.iter().map(|_| None).collect();
+                    let mut nb_chunks = 0;
+                    while !failed.load(Ordering::Relaxed) {
+                        let index = cursor.fetch_add(1, Ordering::Relaxed);
+                        if index >= total {
+                            break;
+                        }
+                        // the last input starting at or before `index`, skipping the empty ones
+                        let file = firsts.partition_point(|&first| first <= index) - 1;
+                        let chunk = segments[file].chunk(index - firsts[file]);
+                        #[cfg(debug_assertions)]
+                        pulled.lock().unwrap()[file].push(chunk);
+                        nb_chunks += 1;
+                        let (sensors, rejects) = tables[chunk.file].get_or_insert_with(|| {
+                            (StationTable::new(format.utf8, tracking), Rejects::default())
+                        });
+                        if let Err(err) = process_chunk(&chunk, format, on_error, sensors, rejects)
+                        {
+                            failed.store(true, Ordering::Relaxed);
+                            return Err(err);
+                        }
+                    }
+                    if verbose {
+                        eprintln!("{tid:?} took {:?} for {nb_chunks} chunks", start.elapsed());
+                    }
+                    Ok(tables)
+                })
+            })
+            .collect::<Vec<ScopedJoinHandle<Result<_, ParseError>>>>();
+
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .map_err(|err| anyhow!("unable to join the thread ({err:?})"))
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+
+    #[cfg(debug_assertions)]
+    if !failed.load(Ordering::Relaxed) {
+        for (segments, mut chunks) in segments.iter().zip(pulled.into_inner().unwrap()) {
+            // as in `chunk_it`, the empty chunks of the records longer than a segment could sort
+            // on either side of the chunk starting at the same offset
+            chunks.retain(|chunk| !chunk.data.is_empty());
+            chunks.sort_by_key(|chunk| chunk.start);
+            let body = segments.body();
+            debug_assert!(
+                crate::chunk::tiles(&chunks, body.start, body.end),
+                "the chunks do not tile [{}, {})",
+                body.start,
+                body.end
+            );
+        }
+    }
+
+    let mut files: Vec<Vec<_>> = segments.iter().map(|_| Vec::new()).collect();
+    let mut first: Option<ParseError> = None;
+    for worker in workers {
+        match worker {
+            Ok(tables) => {
+                for (file, table) in tables.into_iter().enumerate() {
+                    files[file].extend(table);
+                }
+            }
+            Err(err) => {
+                if first
+                    .as_ref()
+                    .is_none_or(|first| (err.file, err.offset) < (first.file, first.offset))
+                {
+                    first = Some(err);
+                }
+            }
+        }
+    }
+    match first {
+        Some(err) => Err(err.into()),
+        None => Ok(files),
+    }
+}
+
+pub(crate) fn process_chunk<'a>(
+    chunk: &Chunk<'a>,
+    format: &Format,
+    on_error: OnError,
+    sensors: &mut StationTable<'a>,
+    rejects: &mut Rejects,
+) -> Result<(), ParseError> {
+    let data = chunk.data;
+    let mut reject = |kind, line: usize, end: usize, span: Range<usize>, name: &[u8]| {
+        let record = format.trim_value(&data[line..end]);
+        match on_error {
+            OnError::Fail => Err(ParseError {
+                kind,
+                file: chunk.file,
+                offset: chunk.start + line,
+                line: 0,
+                record: record.to_vec(),
+                station: unquote(&String::from_utf8_lossy(name)),
+                span,
+            }),
+            OnError::Skip => {
+                rejects.add(kind, None);
+                Ok(())
+            }
+            OnError::Quarantine => {
+                let reject = Reject {
+                    kind,
+                    offset: chunk.start + line,
+                    record: record.to_vec(),
+                };
+                rejects.add(kind, Some(reject));
+                Ok(())
+            }
+        }
+    };
+
+    // parses the record `data[line..end]`, whose last field delimiter is right before `prev`
+    let mut record = |line: usize, prev: usize, end: usize, name: Key<'a>| {
+        if format.is_comment(&data[line..end]) {
+            return Ok(());
+        }
+        let value = format.trim_value(&data[prev..end]);
+        let span = prev - line..prev - line + value.len();
+        if prev == line {
+            return reject(ParseErrorKind::MissingDelimiter, line, end, span, &[]);
+        }
+        if matches!(name.name(), b"" | b"\"\"") {
+            return reject(ParseErrorKind::EmptyName, line, end, 0..0, name.name());
+        }
+        match parse_temp(value) {
+            None => reject(
+                ParseErrorKind::InvalidTemperature,
+                line,
+                end,
+                span,
+                name.name(),
+            ),
+            Some(temp) if !(-999..=999).contains(&temp) => {
+                reject(ParseErrorKind::OutOfRange, line, end, span, name.name())
+            }
+            // record completed
+            Some(temp) => {
+                if !sensors.add(name, temp) {
+                    let span = 0..prev - line - 1;
+                    reject(ParseErrorKind::InvalidUtf8, line, end, span, name.name())?;
+                }
+                Ok(())
+            }
+        }
+    };
+
+    let mut name = Key::new(data, 0, 0);
+    let mut line = 0;
+    let mut prev = 0;
+    let mut quoted = false;
+    // the offset right after the last closing quote, where a quote is an escaped one
+    let mut closed = usize::MAX;
+    for curr in Delimiters::new(data, format.delimiter) {
+        match data[curr] {
+            b'"' if quoted => {
+                quoted = false;
+                closed = curr + 1;
+            }
+            // as per RFC 4180, a quote elsewhere than at the start of a field is a plain char
+            b'"' => quoted = curr == prev || curr == closed,
+            _ if quoted => (),
+            b'\n' => {
+                record(line, prev, curr, name)?;
+                // moving on
+                prev = curr + 1;
+                line = prev;
+            }
+            // the delimiter, the name runs from the start of the record up to the last one
+            _ => {
+                name = Key::new(data, line, curr);
+                // moving on
+                prev = curr + 1;
+            }
+        }
+    }
+
+    // the last record of the input may not be terminated, unless it ends within quotes
+    if line < data.len() {
+        if quoted && !format.is_comment(&data[line..]) {
+            let span = 0..data.len() - line;
+            reject(ParseErrorKind::Truncated, line, data.len(), span, &[])?;
+        } else {
+            record(line, prev, data.len(), name)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Turns a station name into a `Symbol`, stripping the surrounding quotes and unescaping `""`
+/// if quoted.
+fn unquote(text: &str) -> Symbol {
+    match text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+    {
+        Some(inner) => inner.replace("\"\"", "\""),
+        None => text.to_owned(),
+    }
+}
+
+fn merge_results(chunk_results: Vec<(StationTable<'_>, Rejects)>) -> Results {
+    let mut all_sensors = HashMap::default();
+    let mut all_rejects = Rejects::default();
+    for (sensors, rejects) in chunk_results {
+        all_rejects.merge(rejects);
+        merge_table(&mut all_sensors, sensors);
+    }
+    Results::sorted(all_sensors, all_rejects)
+}
+
+/// Adds the sensors of `table` to `sensors`, by unquoted station name
+pub(crate) fn merge_table(sensors: &mut HashMap, table: StationTable<'_>) {
+    for (name, s) in table.into_sensors() {
+        sensors
+            .entry(unquote(&name))
+            .and_modify(|sensor: &mut Sensor| sensor.merge(&s))
+            .or_insert(s);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Rounding, Utf8};
+
+    fn error(aggregator: &Aggregator, data: &[u8]) -> ParseError {
+        let err = aggregator.run(data).unwrap_err();
+        err.downcast::<ParseError>().unwrap()
+    }
+
+    /// A fixed LCG, so that the generated inputs are the same on every run
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, bound: u64) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) % bound
+        }
+    }
+
+    #[test]
+    fn records_longer_than_the_chunks() {
+        let data =
+            b"Abha;1.0\nAbhaAbhaAbhaAbhaAbha;2.0\nAbha;3.0\nAbhaAbhaAbhaAbha;4.0\n".repeat(20);
+        for _ in 0..50 {
+            for chunk_size in [1, 3, 7] {
+                let aggregator = Aggregator::new().threads(4).chunk_size(chunk_size);
+                let results = aggregator.run(&data).unwrap();
+                assert_eq!(results.get("Abha").unwrap().count(), 40);
+                assert_eq!(results.get("AbhaAbhaAbhaAbhaAbha").unwrap().count(), 20);
+                assert_eq!(results.get("AbhaAbhaAbhaAbha").unwrap().count(), 20);
+            }
+        }
+    }
+
+    #[test]
+    fn streamed_like_mapped() {
+        let data =
+            b"\xEF\xBB\xBFstation;temp\n\"New\nYork\";1.0\nAbha;2.0\n\"A\"\"b\";-3.0\nAbha;4.5";
+        let aggregator = Aggregator::new().format(Format::new().header(true));
+        let mapped = format!("{:?}", aggregator.run(data).unwrap().into_vec());
+        for chunk_size in 1..=data.len() + 1 {
+            let streamed = aggregator
+                .clone()
+                .chunk_size(chunk_size)
+                .run_reader(&data[..]);
+            assert_eq!(format!("{:?}", streamed.unwrap().into_vec()), mapped);
+        }
+
+        let invalid = b"Abha;1.0\n\"New\nYork\";1.0\nAbha;2.0\nAbha;x\n";
+        let mapped = error(&aggregator, invalid);
+        assert_eq!(mapped.line, 5);
+        for chunk_size in 1..=invalid.len() + 1 {
+            let streamed = aggregator
+                .clone()
+                .chunk_size(chunk_size)
+                .run_reader(&invalid[..]);
+            let streamed = streamed.unwrap_err().downcast::<ParseError>().unwrap();
+            assert_eq!(streamed, mapped);
+        }
+    }
+
+    #[test]
+    fn stray_quotes() {
+        // a quote only opens a quoted field at its start, elsewhere it is part of the name
+        let data = b"Abha;1.0\nq\"x;3.0\nAbha;2.0\n6\" Pipe;-1.0\nBaku;4.0\n";
+        let aggregator = Aggregator::new().on_error(OnError::Skip);
+        for chunks in 1..=data.len() + 1 {
+            for threads in [1, 2, 4] {
+                let aggregator = aggregator.clone().threads(threads).chunks(chunks);
+                let mapped = aggregator.run(data).unwrap();
+                let streamed = aggregator.chunk_size(chunks).run_reader(&data[..]).unwrap();
+                for results in [mapped, streamed] {
+                    let names: Vec<_> = results.iter().map(|(name, _)| name).collect();
+                    assert_eq!(names, ["6\" Pipe", "Abha", "Baku", "q\"x"]);
+                    assert_eq!(results.get("Abha").unwrap().count(), 2);
+                    assert!(results.rejects().is_empty());
+                }
+            }
+        }
+
+        // but it still opens one at the start of the temperature
+        let err = error(&Aggregator::new(), b"Abha;\"1.0\nBaku;4.0\n");
+        assert_eq!((err.kind, err.line), (ParseErrorKind::Truncated, 1));
+    }
+
+    #[test]
+    fn escaped_newlines_and_quotes() {
+        let data = b"\"New\nYork\";1.0\n\"6\"\" \nPipe\";2.0\n\"\"\"\n\";3.0\n\"New\nYork\";5.0\n\"\"\"\";4.0";
+        for chunks in 1..=data.len() + 1 {
+            let aggregator = Aggregator::new().chunks(chunks);
+            let mapped = aggregator.run(data).unwrap();
+            let streamed = aggregator.chunk_size(chunks).run_reader(&data[..]).unwrap();
+            for results in [mapped, streamed] {
+                let names: Vec<_> = results.iter().map(|(name, _)| name).collect();
+                assert_eq!(names, ["\"", "\"\n", "6\" \nPipe", "New\nYork"]);
+                assert_eq!(results.get("New\nYork").unwrap().mean(), 3.0);
+            }
+        }
+    }
+
+    #[test]
+    fn names_with_delimiters() {
+        let csv = Format::new().delimiter(b',');
+        let data = b"Washington, D.C.,2.0\n\"Washington, D.C.\",4.0\na;b,1.0\n";
+        let semicolons = b"a;b;1.0\na;b;3.0\n";
+        for chunks in [1, 3, data.len()] {
+            let results = Aggregator::new()
+                .chunks(chunks)
+                .format(csv)
+                .run(data)
+                .unwrap();
+            let names: Vec<_> = results.iter().map(|(name, _)| name).collect();
+            assert_eq!(names, ["Washington, D.C.", "a;b"]);
+            assert_eq!(results.get("Washington, D.C.").unwrap().mean(), 3.0);
+
+            let results = Aggregator::new().chunks(chunks).run(semicolons).unwrap();
+            let names: Vec<_> = results.iter().map(|(name, _)| name).collect();
+            assert_eq!(names, ["a;b"]);
+            assert_eq!(results.get("a;b").unwrap().mean(), 2.0);
+        }
+
+        let err = error(&Aggregator::new(), b"a;b;x\n");
+        assert_eq!(
+            (err.kind, err.station.as_str()),
+            (ParseErrorKind::InvalidTemperature, "a;b")
+        );
+        assert_eq!(err.span, 4..5);
+    }
+
+    #[test]
+    fn sketch_percentiles_within_accuracy() {
+        // normal-ish readings around 15 degrees
+        let mut lcg = Lcg(42);
+        let mut readings = Vec::new();
+        let mut data = Vec::new();
+        for _ in 0..50_000 {
+            let tenths = (0..4).map(|_| lcg.next(400) as i64).sum::<i64>() - 650;
+            readings.push(tenths as f64 / 10.0);
+            data.extend(format!("Abha;{:.1}\n", tenths as f64 / 10.0).bytes());
+        }
+        readings.sort_by(f64::total_cmp);
+
+        let accuracy = 0.01;
+        let aggregator = Aggregator::new().sketches(Some(accuracy));
+        let results = aggregator.clone().chunk_size(4096).run(&data).unwrap();
+        let abha = results.get("Abha").unwrap();
+        for p in [0.0, 1.0, 10.0, 25.0, 50.0, 75.0, 90.0, 99.0, 99.9, 100.0] {
+            let estimate = abha.percentile(p).unwrap();
+            let rank = (p / 100.0 * (readings.len() - 1) as f64) as usize;
+            let exact = readings[rank];
+            assert!(
+                (estimate - exact).abs() <= accuracy * exact.abs() + 1e-9,
+                "p{p}"
+            );
+
+            // so the exact percentile ranks between the readings close enough to the estimate
+            let band = estimate.abs() * accuracy / (1.0 - accuracy) + 1e-9;
+            let below = readings.partition_point(|&reading| reading < estimate - band);
+            let within = readings.partition_point(|&reading| reading <= estimate + band);
+            assert!(below <= rank && rank < within, "p{p}");
+        }
+
+        // the sketches merge exactly, whatever the chunks
+        let single = aggregator.threads(1).run(&data).unwrap();
+        for p in [0.0, 25.0, 50.0, 99.9] {
+            let single = single.get("Abha").unwrap().percentile(p);
+            assert_eq!(single, abha.percentile(p));
+        }
+    }
+
+    #[test]
+    fn exact_means_of_large_inputs() {
+        const STATIONS: usize = 97;
+        let mut lcg = Lcg(7);
+        let mut data = Vec::new();
+        // the reference sums and counts, in tenths of degree
+        let mut reference = vec![(i16::MAX, 0i128, 0i128, i16::MIN); STATIONS];
+        for _ in 0..3_000_000 {
+            let station = lcg.next(STATIONS as u64) as usize;
+            // skewed towards the high temperatures, so that the sums grow large
+            let temp = (lcg.next(1999) as i16 - 999).max(lcg.next(1999) as i16 - 999);
+            let (min, sum, count, max) = &mut reference[station];
+            *min = (*min).min(temp);
+            *max = (*max).max(temp);
+            *sum += temp as i128;
+            *count += 1;
+            let sign = if temp < 0 { "-" } else { "" };
+            let abs = temp.unsigned_abs();
+            data.extend(format!("s{station};{sign}{}.{}\n", abs / 10, abs % 10).bytes());
+        }
+
+        for chunks in [1, 7, 64, 1000] {
+            let results = Aggregator::new()
+                .threads(4)
+                .chunks(chunks)
+                .run(&data)
+                .unwrap();
+            assert_eq!(results.len(), STATIONS);
+            for (station, &(min, sum, count, max)) in reference.iter().enumerate() {
+                let sensor = results.get(&format!("s{station}")).unwrap();
+                assert_eq!(sensor.min_tenths(), min);
+                assert_eq!(sensor.max_tenths(), max);
+                assert_eq!(sensor.sum_tenths() as i128, sum);
+                assert_eq!(sensor.count() as i128, count);
+
+                // the mean in tenths, rounded half up: floor(sum / count + 1 / 2)
+                let tenths = (2 * sum + count).div_euclid(2 * count);
+                let sign = if tenths < 0 { "-" } else { "" };
+                let abs = tenths.unsigned_abs();
+                let mean = format!("{sign}{}.{}", abs / 10, abs % 10);
+                assert_eq!(sensor.format(Rounding::CeilHalf, 1)[1], mean);
+                assert!((sensor.mean() - sum as f64 / count as f64 / 10.0).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_temperatures() {
+        for (value, kind) in [
+            ("100.0", ParseErrorKind::OutOfRange),
+            ("-100.0", ParseErrorKind::OutOfRange),
+            ("1000000.0", ParseErrorKind::OutOfRange),
+            ("-99999999999.9", ParseErrorKind::OutOfRange),
+            ("1e3", ParseErrorKind::InvalidTemperature),
+            ("1000000", ParseErrorKind::InvalidTemperature),
+        ] {
+            let err = error(&Aggregator::new(), format!("Abha;{value}\n").as_bytes());
+            assert_eq!(err.kind, kind, "{value}");
+        }
+    }
+
+    #[test]
+    fn errors_of_non_utf8_records() {
+        let aggregator = Aggregator::new();
+        let err = error(&aggregator, b"Abha;1.0\n\xff\xff;1.0\n");
+        assert_eq!(err.kind, ParseErrorKind::InvalidUtf8);
+        assert_eq!(err.record, b"\xff\xff;1.0");
+        assert_eq!(err.span, 0..2);
+        assert_eq!(
+            err.to_string(),
+            "station name is not valid UTF-8 \"\u{FFFD}\u{FFFD}\" of station \"\u{FFFD}\u{FFFD}\" \
+             at line 2 (byte offset 9)"
+        );
+
+        let lossy = aggregator.format(Format::new().utf8(Utf8::Lossy));
+        let err = error(&lossy, b"\xff;x\n");
+        assert_eq!(err.kind, ParseErrorKind::InvalidTemperature);
+        assert_eq!(&err.record[err.span.clone()], b"x");
+        assert_eq!(err.station, "\u{FFFD}");
+    }
+}
@@ -0,0 +1,267 @@
+//! Arrow and Parquet export of the results, behind the `arrow` feature.
+//!
+//! The results are laid out as one row per station, in name order, with the columns:
+//!
+//! | column    | type      |
+//! |-----------|-----------|
+//! | `station` | `Utf8`    |
+//! | `min`     | `Float64` |
+//! | `mean`    | `Float64` |
+//! | `max`     | `Float64` |
+//! | `count`   | `UInt64`  |
+//!
+//! followed by the nullable `variance`, `stddev`, `skewness` and `kurtosis` `Float64` columns
+//! when the moments are tracked, and by a nullable `p<percentile>` `Float64` column for each of the
+//! percentiles asked for.
+
+use std::io::Write;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, UInt64Array};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+use crate::output::Output;
+use crate::{Results, Sensor};
+
+/// The schema of the batches built by [`record_batch`], with the moments columns if `moments` and
+/// a column for each of the `percentiles`
+pub fn schema(moments: bool, percentiles: &[f64]) -> SchemaRef {
+    let mut fields = vec![
+        Field::new("station", DataType::Utf8, false),
+        Field::new("min", DataType::Float64, false),
+        Field::new("mean", DataType::Float64, false),
+        Field::new("max", DataType::Float64, false),
+        Field::new("count", DataType::UInt64, false),
+    ];
+    if moments {
+        for name in ["variance", "stddev", "skewness", "kurtosis"] {
+            fields.push(Field::new(name, DataType::Float64, true));
+        }
+    }
+    for p in percentiles {
+        fields.push(Field::new(format!("p{p}"), DataType::Float64, true));
+    }
+    Arc::new(Schema::new(fields))
+}
+
+/// Builds a single batch holding all the stations of `results`, with their `percentiles` (in
+/// [0, 100]) when the histograms or the sketches are kept
+pub fn record_batch(results: &Results, percentiles: &[f64]) -> Result<RecordBatch> {
+    let stations = StringArray::from_iter_values(results.iter().map(|(name, _)| name));
+    let column = |value: fn(&Sensor) -> f64| -> ArrayRef {
+        Arc::new(Float64Array::from_iter_values(
+            results.iter().map(|(_, sensor)| value(sensor)),
+        ))
+    };
+    let nullable = |value: fn(&Sensor) -> Option<f64>| -> ArrayRef {
+        Arc::new(Float64Array::from_iter(
+            results.iter().map(|(_, sensor)| value(sensor)),
+        ))
+    };
+    let counts =
+        UInt64Array::from_iter_values(results.iter().map(|(_, sensor)| sensor.count() as u64));
+
+    let mut columns = vec![
+        Arc::new(stations) as ArrayRef,
+        column(|sensor| sensor.min()),
+        column(|sensor| sensor.mean()),
+        column(|sensor| sensor.max()),
+        Arc::new(counts),
+    ];
+    let moments = results.has_moments();
+    if moments {
+        columns.extend([
+            nullable(|sensor| sensor.variance()),
+            nullable(|sensor| sensor.std_dev()),
+            nullable(|sensor| sensor.skewness()),
+            nullable(|sensor| sensor.kurtosis()),
+        ]);
+    }
+    for &p in percentiles {
+        columns.push(Arc::new(Float64Array::from_iter(
+            results.iter().map(|(_, sensor)| sensor.percentile(p)),
+        )));
+    }
+    RecordBatch::try_new(schema(moments, percentiles), columns)
+        .context("unable to build the record batch")
+}
+
+/// An Arrow IPC file holding a single [`record_batch`]
+#[derive(Clone, Debug, Default)]
+pub struct Ipc {
+    pub percentiles: Vec<f64>,
+}
+
+impl Output for Ipc {
+    fn write(&self, results: &Results, writer: &mut dyn Write) -> Result<()> {
+        let batch = record_batch(results, &self.percentiles)?;
+        let mut writer = arrow_ipc::writer::FileWriter::try_new(writer, &batch.schema())
+            .context("unable to write")?;
+        writer.write(&batch).context("unable to write")?;
+        writer.finish().context("unable to write")?;
+        Ok(())
+    }
+}
+
+/// A Parquet file holding a single [`record_batch`] as one row group, Snappy compressed
+#[derive(Clone, Debug, Default)]
+pub struct Parquet {
+    pub percentiles: Vec<f64>,
+}
+
+impl Output for Parquet {
+    fn write(&self, results: &Results, writer: &mut dyn Write) -> Result<()> {
+        let batch = record_batch(results, &self.percentiles)?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        // the parquet writer requires a `Send` sink, the file is small enough to be buffered
+        let mut buffer = Vec::new();
+        let mut parquet = ArrowWriter::try_new(&mut buffer, batch.schema(), Some(properties))
+            .context("unable to write")?;
+        parquet.write(&batch).context("unable to write")?;
+        parquet.close().context("unable to write")?;
+        writer.write_all(&buffer).context("unable to write")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Cursor;
+
+    use arrow_array::Array;
+    use arrow_ipc::reader::FileReader;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use super::*;
+    use crate::{Aggregator, Format};
+
+    const DATA: &[u8] = b"b;-2.0\na;1.0\na;3.0\na;2.0\n";
+
+    fn results(data: &[u8]) -> Results {
+        Aggregator::new()
+            .moments(true)
+            .histograms(true)
+            .run(data)
+            .unwrap()
+    }
+
+    fn values(batch: &RecordBatch, name: &str) -> Vec<Option<f64>> {
+        let column = batch.column_by_name(name).unwrap();
+        let column = column.as_any().downcast_ref::<Float64Array>().unwrap();
+        column.iter().collect()
+    }
+
+    #[test]
+    fn batches_of_results() {
+        let results = results(DATA);
+        let batch = record_batch(&results, &[50.0, 99.9]).unwrap();
+        let names: Vec<_> = batch
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "station", "min", "mean", "max", "count", "variance", "stddev", "skewness",
+                "kurtosis", "p50", "p99.9"
+            ]
+        );
+        assert_eq!(batch.schema(), schema(true, &[50.0, 99.9]));
+        assert_eq!(batch.num_rows(), 2);
+
+        let stations = batch.column_by_name("station").unwrap();
+        let stations = stations.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(stations.iter().collect::<Vec<_>>(), [Some("a"), Some("b")]);
+        let counts = batch.column_by_name("count").unwrap();
+        let counts = counts.as_any().downcast_ref::<UInt64Array>().unwrap();
+        assert_eq!(counts.values(), &[3, 1]);
+        assert_eq!(values(&batch, "min"), [Some(1.0), Some(-2.0)]);
+        assert_eq!(values(&batch, "mean"), [Some(2.0), Some(-2.0)]);
+        assert_eq!(values(&batch, "max"), [Some(3.0), Some(-2.0)]);
+        for (column, value) in [
+            ("variance", Sensor::variance as fn(&Sensor) -> Option<f64>),
+            ("stddev", Sensor::std_dev),
+            ("skewness", Sensor::skewness),
+            ("kurtosis", Sensor::kurtosis),
+        ] {
+            let expected: Vec<_> = results.iter().map(|(_, sensor)| value(sensor)).collect();
+            assert_eq!(values(&batch, column), expected, "{column}");
+        }
+        let variance = values(&batch, "variance")[0].unwrap();
+        assert!((variance - 2.0 / 3.0).abs() < 1e-12);
+        assert_eq!(values(&batch, "p50"), [Some(2.0), Some(-2.0)]);
+
+        // without the moments nor the percentiles
+        let plain = Aggregator::new().run(DATA).unwrap();
+        let batch = record_batch(&plain, &[]).unwrap();
+        assert_eq!(batch.schema(), schema(false, &[]));
+        assert_eq!(batch.num_columns(), 5);
+    }
+
+    #[test]
+    fn empty_results() {
+        let results = results(b"");
+        let batch = record_batch(&results, &[50.0]).unwrap();
+        assert_eq!(batch.num_rows(), 0);
+        // without any sensor, the moments are not known to be tracked
+        assert_eq!(batch.schema(), schema(false, &[50.0]));
+
+        let plain = Aggregator::new()
+            .format(Format::new().header(true))
+            .run(b"station;temperature\n")
+            .unwrap();
+        let batch = record_batch(&plain, &[]).unwrap();
+        assert_eq!((batch.num_rows(), batch.num_columns()), (0, 5));
+    }
+
+    #[test]
+    fn ipc_round_trip() {
+        for data in [DATA, b""] {
+            let results = results(data);
+            let percentiles = vec![25.0, 50.0];
+            let mut out = Vec::new();
+            let ipc = Ipc {
+                percentiles: percentiles.clone(),
+            };
+            ipc.write(&results, &mut out).unwrap();
+
+            let reader = FileReader::try_new(Cursor::new(out), None).unwrap();
+            let batches: Vec<_> = reader.map(Result::unwrap).collect();
+            assert_eq!(batches, [record_batch(&results, &percentiles).unwrap()]);
+        }
+    }
+
+    #[test]
+    fn parquet_round_trip() {
+        let path = std::env::temp_dir().join(format!("chunkit-{}.parquet", std::process::id()));
+        for data in [DATA, b""] {
+            let results = results(data);
+            let percentiles = vec![25.0, 50.0];
+            let mut out = File::create(&path).unwrap();
+            let parquet = Parquet {
+                percentiles: percentiles.clone(),
+            };
+            parquet.write(&results, &mut out).unwrap();
+
+            let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap());
+            let reader = builder.unwrap().build().unwrap();
+            let batches: Vec<_> = reader.map(Result::unwrap).collect();
+            let batch = record_batch(&results, &percentiles).unwrap();
+            assert_eq!(batches.len(), usize::from(batch.num_rows() > 0));
+            for read in batches {
+                assert_eq!(read.schema().fields(), batch.schema().fields());
+                assert_eq!(read.columns(), batch.columns());
+            }
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
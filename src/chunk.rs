@@ -0,0 +1,392 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use anyhow::{Error, Result};
+
+use crate::Format;
+
+/// A chunk contains lines without overlapping
+///
+/// `data` is the input between the absolute offsets `start` and `end`, so that
+/// `end - start == data.len()`.
+#[derive(Clone, Copy, Debug)]
+pub struct Chunk<'a> {
+    pub data: &'a [u8],
+    pub start: usize,
+    pub end: usize,
+    /// Index of the input the chunk belongs to, the offsets being relative to it
+    pub file: usize,
+}
+
+/// Splits `buf` into at most `nb_chunks` chunks that each start at the beginning of a record.
+///
+/// There are never more chunks than bytes, so an input without any record yields no chunk.
+///
+/// Station names may be quoted (`"Foo;Bar"`, with `""` as an escaped quote) and a quoted name
+/// can contain LF chars, so the quotes before each nominal split point are followed to tell
+/// whether it lies inside a quoted field before moving it to the closest record terminator.
+///
+/// The leading BOM and the header of `format`, if any, are not part of any chunk. The chunks
+/// tile the rest of the input exactly, including a last record without terminator.
+///
+/// ```
+/// use chunkit::{chunk_it, Format};
+///
+/// let buf = b"station;temperature\nAbha;1.0\n\"Foo\nBar\";2.0\nAbha;3.0";
+/// let chunks = chunk_it(buf, 3, &Format::new().header(true))?;
+/// let data: Vec<_> = chunks.iter().map(|chunk| chunk.data).collect();
+/// assert_eq!(data, [&b"Abha;1.0\n\"Foo\nBar\";2.0\n"[..], b"Abha;3.0"]);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn chunk_it<'a>(
+    buf: &'a [u8],
+    nb_chunks: usize,
+    format: &Format,
+) -> Result<Vec<Chunk<'a>>, Error> {
+    let eof = buf.len() - format.body_start(buf);
+    let segments = Segments::new(buf, 0, format, eof.div_ceil(nb_chunks.max(1)))?;
+    let chunks: Vec<_> = (0..segments.len())
+        .map(|index| segments.chunk(index))
+        .filter(|chunk| !chunk.data.is_empty())
+        .collect();
+
+    let body = segments.body();
+    debug_assert!(
+        tiles(&chunks, body.start, body.end),
+        "the chunks do not tile [{}, {})",
+        body.start,
+        body.end
+    );
+    Ok(chunks)
+}
+
+/// The quoting state before a segment is not known yet
+const UNKNOWN: u8 = 0;
+
+/// The quoting state at an offset of an input
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Quoting {
+    /// Outside of any quoted field
+    Unquoted = 1,
+    /// Within a quoted field
+    Quoted = 2,
+    /// Right after the closing quote of a field, where a quote is an escaped one
+    Closed = 3,
+}
+
+impl Quoting {
+    const ALL: [Quoting; 3] = [Quoting::Unquoted, Quoting::Quoted, Quoting::Closed];
+
+    fn from_u8(state: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(state).checked_sub(1)?).copied()
+    }
+}
+
+/// The quoting rules of an input, as per RFC 4180: a quote only opens a quoted field at the
+/// start of a field, elsewhere it is a plain char of an unquoted one (eg. `6" Pipe;1.0`). Within a
+/// quoted field, a quote closes it unless it is followed by another one, an escaped quote.
+#[derive(Clone, Copy)]
+pub(crate) struct Quotes<'a> {
+    buf: &'a [u8],
+    /// Offset of the first record
+    base: usize,
+    delimiter: u8,
+}
+
+impl<'a> Quotes<'a> {
+    pub(crate) fn new(buf: &'a [u8], base: usize, delimiter: u8) -> Self {
+        Self {
+            buf,
+            base,
+            delimiter,
+        }
+    }
+
+    /// The state after `buf[index]`, from the `state` before it
+    #[inline]
+    pub(crate) fn next(&self, state: Quoting, index: usize) -> Quoting {
+        match (state, self.buf[index]) {
+            (Quoting::Quoted, b'"') => Quoting::Closed,
+            (Quoting::Quoted, _) => Quoting::Quoted,
+            (Quoting::Closed, b'"') => Quoting::Quoted,
+            (Quoting::Unquoted, b'"') if self.is_field_start(index) => Quoting::Quoted,
+            _ => Quoting::Unquoted,
+        }
+    }
+
+    /// Whether `index`, outside of any quoted field, is the start of a field
+    #[inline]
+    fn is_field_start(&self, index: usize) -> bool {
+        index == self.base
+            || matches!(self.buf[index - 1], b'\n')
+            || self.buf[index - 1] == self.delimiter
+    }
+
+    /// Returns the index of the first LF at or after `offset` that terminates a record, ie. that
+    /// is not part of a quoted field. `state` is the state at `offset`.
+    pub(crate) fn record_end(&self, offset: usize, mut state: Quoting) -> Option<usize> {
+        for index in offset..self.buf.len() {
+            if self.buf[index] == b'\n' && state != Quoting::Quoted {
+                return Some(index);
+            }
+            state = self.next(state, index);
+        }
+        None
+    }
+
+    /// The states at the end of `range` for each of the states at its start, in the order of
+    /// `Quoting::ALL`. Only the quotes are looked at, so that it is about as fast as counting them
+    fn states_after(&self, range: Range<usize>) -> [Quoting; 3] {
+        let mut states = Quoting::ALL;
+        // the offset right after the last quote, where a closed field can still be reopened
+        let mut next = range.start;
+        for index in range.clone().filter(|&index| self.buf[index] == b'"') {
+            for state in &mut states {
+                if index != next && *state == Quoting::Closed {
+                    *state = Quoting::Unquoted;
+                }
+                *state = self.next(*state, index);
+            }
+            next = index + 1;
+        }
+        if next != range.end {
+            states = states.map(|state| match state {
+                Quoting::Closed => Quoting::Unquoted,
+                state => state,
+            });
+        }
+        states
+    }
+}
+
+/// The body of an input split into nominal segments of `size` bytes, whose chunks are built
+/// lazily, typically by the workers pulling their indices from a shared cursor.
+///
+/// Station names may be quoted (`"Foo;Bar"`, with `""` as an escaped quote) and a quoted name
+/// can contain LF chars, so a nominal split point cannot simply be moved to the next LF. Building
+/// chunk `i` follows the quotes of segment `i` from each of the quoting states it may start in,
+/// and publishes the state at its end once the one at its start is known. This tells whether its
+/// split points lie inside a quoted field, see [`Quotes`]. Only then is the closest record
+/// terminator looked up. Both LF and CRLF records end with a LF, so the boundary is right
+/// after it.
+///
+/// Chunk `i` spans from the boundary after the start of segment `i` to the one after its end,
+/// so the chunks tile the body exactly. A chunk is empty when a record is longer than a segment.
+pub(crate) struct Segments<'a> {
+    buf: &'a [u8],
+    /// Index of the input, see [`Chunk::file`]
+    file: usize,
+    /// Offset of the first record
+    base: usize,
+    quotes: Quotes<'a>,
+    size: usize,
+    len: usize,
+    /// The [`Quoting`] state before each segment, and at eof
+    states: Vec<AtomicU8>,
+}
+
+impl<'a> Segments<'a> {
+    pub(crate) fn new(buf: &'a [u8], file: usize, format: &Format, size: usize) -> Result<Self> {
+        format.check()?;
+        let base = format.body_start(buf);
+        let size = size.max(1);
+        let len = (buf.len() - base).div_ceil(size);
+        let states: Vec<_> = (0..=len).map(|_| AtomicU8::new(UNKNOWN)).collect();
+        states[0].store(Quoting::Unquoted as u8, Ordering::Release);
+        Ok(Self {
+            buf,
+            file,
+            base,
+            quotes: Quotes::new(buf, base, format.delimiter),
+            size,
+            len,
+            states,
+        })
+    }
+
+    /// The number of chunks
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The offsets of the records, which the chunks tile
+    pub(crate) fn body(&self) -> Range<usize> {
+        self.base..self.buf.len()
+    }
+
+    /// Builds the chunk `index`, each index at most once.
+    ///
+    /// Waits until the chunk `index - 1` has been started, so the indices must be handed out in
+    /// order (and the previous ones built concurrently).
+    pub(crate) fn chunk(&self, index: usize) -> Chunk<'a> {
+        let eof = self.buf.len();
+        let start = self.base + index * self.size;
+        let end = (start + self.size).min(eof);
+
+        let states = self.quotes.states_after(start..end);
+        let state = loop {
+            match Quoting::from_u8(self.states[index].load(Ordering::Acquire)) {
+                None => std::thread::yield_now(),
+                Some(state) => break state,
+            }
+        };
+        let state_after = states[state as usize - 1];
+        self.states[index + 1].store(state_after as u8, Ordering::Release);
+
+        let start = match index {
+            0 => start,
+            _ => self.boundary(start, state),
+        };
+        let end = self.boundary(end, state_after);
+        Chunk {
+            data: &self.buf[start..end],
+            start,
+            end,
+            file: self.file,
+        }
+    }
+
+    /// The start of the first record at or after `offset`, `state` being the state at `offset`
+    fn boundary(&self, offset: usize, state: Quoting) -> usize {
+        match self.quotes.record_end(offset, state) {
+            Some(lf) => lf + 1,
+            None => self.buf.len(),
+        }
+    }
+}
+
+/// Whether the `chunks` cover `[start, end)` exactly, back to back and without overlap
+pub(crate) fn tiles(chunks: &[Chunk<'_>], start: usize, end: usize) -> bool {
+    let mut offset = start;
+    for chunk in chunks {
+        if chunk.start != offset || chunk.end - chunk.start != chunk.data.len() {
+            return false;
+        }
+        offset = chunk.end;
+    }
+    offset == end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LineEnding;
+
+    /// The offsets of the records of `buf[start..]`, following the quotes byte by byte
+    fn record_starts(buf: &[u8], start: usize, delimiter: u8) -> Vec<usize> {
+        let quotes = Quotes::new(buf, start, delimiter);
+        let mut starts = vec![start];
+        while let Some(lf) = quotes.record_end(*starts.last().unwrap(), Quoting::Unquoted) {
+            starts.push(lf + 1);
+        }
+        starts
+    }
+
+    #[test]
+    fn chunks_tile_the_body() {
+        let header = Format::new().header(true);
+        let inputs: [(&[u8], Format, usize); 9] = [
+            (
+                b"station;temperature\nAbha;1.0\n\"Foo\nBar\";2.0\nAbha;3.0",
+                header,
+                20,
+            ),
+            (
+                b"Abha;1.0\n\"Foo\nBar\";2.0\n\"\"\"\n\";3.0\n",
+                Format::new(),
+                0,
+            ),
+            (b"\xEF\xBB\xBFAbha;1.0\r\nAbha;2.0\r\n", Format::new(), 3),
+            (
+                b"\xEF\xBB\xBF\"Foo\nBar\";1.0\nAbha;2.0\n",
+                Format::new(),
+                3,
+            ),
+            (
+                b"station;temp\r\nAbha;1.0\r\n",
+                header.line_ending(LineEnding::CrLf),
+                14,
+            ),
+            (b"station;temperature", header, 19),
+            (b"", Format::new(), 0),
+            // stray quotes within unquoted fields
+            (
+                b"Abha;1.0\nq\"x;3.0\n6\" Pipe;2.0\nBaku;4.0\n\"a\"\"\nb\";1.0\n",
+                Format::new(),
+                0,
+            ),
+            (
+                b"\"st\"\"n\",\"te\nmp\"\nq\"x,3.0\n\"a,b\",\"1.0\nBaku,4.0\n",
+                header.delimiter(b','),
+                16,
+            ),
+        ];
+        for (buf, format, start) in inputs {
+            let starts = record_starts(buf, start, format.delimiter);
+            for nb_chunks in 1..=buf.len() + 1 {
+                let chunks = chunk_it(buf, nb_chunks, &format).unwrap();
+                assert!(tiles(&chunks, start, buf.len()), "{nb_chunks} chunks");
+                assert!(chunks.len() <= nb_chunks);
+                for chunk in &chunks {
+                    assert_eq!(chunk.data, &buf[chunk.start..chunk.end]);
+                    assert!(starts.contains(&chunk.start), "{nb_chunks} chunks");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn quoted_record_ends() {
+        let cases: [(&[u8], usize, Quoting, Option<usize>); 10] = [
+            (b"a;1.0\nb", 0, Quoting::Unquoted, Some(5)),
+            (b"\"a\nb\";1.0\n", 0, Quoting::Unquoted, Some(9)),
+            (b"a\n\";1.0\nb", 0, Quoting::Quoted, Some(7)),
+            (b"\"a\n", 0, Quoting::Unquoted, None),
+            // escaped quotes
+            (b"\"a\"\"\nb\";1.0\n", 0, Quoting::Unquoted, Some(11)),
+            (b"\"\n\";1.0\n", 0, Quoting::Closed, Some(7)),
+            (b"\n\";1.0\n", 0, Quoting::Closed, Some(0)),
+            // a quote only opens a field at its start
+            (b"q\"x;1.0\nb\";2.0\n", 0, Quoting::Unquoted, Some(7)),
+            (b"a;\"1.0\nb;2.0\n", 0, Quoting::Unquoted, None),
+            (b"a\"\"b\nc", 0, Quoting::Unquoted, Some(4)),
+        ];
+        for (buf, offset, state, end) in cases {
+            let quotes = Quotes::new(buf, 0, b';');
+            assert_eq!(quotes.record_end(offset, state), end, "{buf:?}");
+        }
+    }
+
+    #[test]
+    fn quote_states_of_segments() {
+        // every range of random inputs, against following their bytes one by one
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        for len in 0..64 {
+            let buf: Vec<u8> = (0..len)
+                .map(|_| {
+                    seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    b"\";\na"[(seed >> 33) as usize % 4]
+                })
+                .collect();
+            let quotes = Quotes::new(&buf, 0, b';');
+            for start in 0..=len {
+                for end in start..=len {
+                    let states = Quoting::ALL
+                        .map(|state| (start..end).fold(state, |state, i| quotes.next(state, i)))
+                        .map(|state| match state {
+                            Quoting::Closed if end > start && buf[end - 1] != b'"' => {
+                                Quoting::Unquoted
+                            }
+                            state => state,
+                        });
+                    assert_eq!(
+                        quotes.states_after(start..end),
+                        states,
+                        "{buf:?}[{start}..{end}]"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,462 @@
+//! Transparent decompression of the inputs.
+//!
+//! The decoders are behind the `gzip`, `zstd` and `lz4` features, while [`Compression::detect`]
+//! is always available to tell which one an input needs.
+
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+/// The compression formats of the inputs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// gzip, including the BGZF flavour of bgzip whose blocks are decoded in parallel
+    Gzip,
+    /// Zstandard, whose frames are decoded in parallel
+    Zstd,
+    /// LZ4 frames
+    Lz4,
+}
+
+/// Compressed bytes decoded by each thread per round of parallel decoding
+const ROUND_SIZE: usize = 1 << 20;
+
+impl Compression {
+    /// Detects the compression of an input from its first bytes or, when they do not tell, from
+    /// the extension of its `path`.
+    ///
+    /// ```
+    /// use chunkit::decode::Compression;
+    /// use std::path::Path;
+    ///
+    /// assert_eq!(Compression::detect(b"\x28\xb5\x2f\xfd...", None), Some(Compression::Zstd));
+    /// assert_eq!(Compression::detect(b"Abha;1.0\n", Some(Path::new("a.txt"))), None);
+    /// assert_eq!(Compression::detect(b"????", Some(Path::new("a.gz"))), Some(Compression::Gzip));
+    /// assert_eq!(Compression::detect(b"", Some(Path::new("a.gz"))), None);
+    /// ```
+    pub fn detect(magic: &[u8], path: Option<&Path>) -> Option<Compression> {
+        match magic {
+            [] => None,
+            [0x1f, 0x8b, ..] => Some(Compression::Gzip),
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(Compression::Zstd),
+            [0x04, 0x22, 0x4d, 0x18, ..] => Some(Compression::Lz4),
+            _ => match path?.extension()?.to_str()? {
+                "gz" | "gzip" | "bgz" => Some(Compression::Gzip),
+                "zst" | "zstd" => Some(Compression::Zstd),
+                "lz4" => Some(Compression::Lz4),
+                _ => None,
+            },
+        }
+    }
+
+    /// The cargo feature enabling the decoder
+    fn feature(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    /// Splits `buf` into frames that can be decoded independently, if it has some
+    // without the `gzip` and `zstd` features, no compression has independent frames
+    #[allow(unused_variables, unreachable_code)]
+    fn frames(&self, buf: &[u8]) -> Option<Vec<Range<usize>>> {
+        let frame_len: fn(&[u8]) -> Option<usize> = match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => bgzf_block_len,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => |buf| zstd::zstd_safe::find_frame_compressed_size(buf).ok(),
+            _ => return None,
+        };
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        while offset < buf.len() {
+            let len = frame_len(&buf[offset..]).filter(|&len| len > 0)?;
+            frames.push(offset..offset + len);
+            offset += len;
+        }
+        Some(frames)
+    }
+
+    /// Decodes a single frame, see `frames`
+    #[allow(unused_variables)]
+    fn decode_frame(&self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                // the last 4 bytes of a gzip member hold its decompressed size, which is only a
+                // hint: it is not checked until the end, so it is capped to the 64 KiB of a BGZF
+                // block rather than trusted with an allocation
+                let size = frame
+                    .get(frame.len().saturating_sub(4)..)
+                    .and_then(|size| size.try_into().ok())
+                    .map_or(0, u32::from_le_bytes);
+                let mut out = Vec::with_capacity((size as usize).min(1 << 16));
+                flate2::read::GzDecoder::new(frame).read_to_end(&mut out)?;
+                Ok(out)
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::stream::decode_all(frame),
+            _ => unreachable!("{self:?} has no frames"),
+        }
+    }
+}
+
+/// Decompresses `buf`, decoding its frames on `threads` threads when it is made of several
+/// independent ones (multi-frame zstd or BGZF), sequentially otherwise
+pub fn decompress<'a>(
+    buf: &'a [u8],
+    compression: Compression,
+    threads: usize,
+) -> Result<Box<dyn Read + 'a>> {
+    match compression.frames(buf) {
+        Some(frames) if frames.len() > 1 && threads > 1 => Ok(Box::new(Frames {
+            buf,
+            compression,
+            frames: frames.into(),
+            threads,
+            parts: VecDeque::new(),
+            offset: 0,
+        })),
+        _ => decompress_reader(buf, compression),
+    }
+}
+
+/// Decompresses `reader` sequentially, eg. stdin
+// without any decoder feature, every compression fails
+#[allow(unused_variables, unreachable_code)]
+pub fn decompress_reader<'a>(
+    reader: impl Read + 'a,
+    compression: Compression,
+) -> Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Box::new(zstd::stream::Decoder::new(reader)?),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => Box::new(Lz4Frames(Some(lz4_flex::frame::FrameDecoder::new(Eof {
+            reader,
+            eof: false,
+        })))),
+        #[allow(unreachable_patterns)]
+        _ => bail!(
+            "{compression:?} input, chunkit must be built with the `{}` feature",
+            compression.feature()
+        ),
+    })
+}
+
+/// Concatenated LZ4 frames, the decoder stopping at the end of each frame.
+///
+/// Each frame gets a decoder of its own, as the frames may have different block sizes. The
+/// decoder is only `None` while being replaced.
+#[cfg(feature = "lz4")]
+struct Lz4Frames<R: Read>(Option<lz4_flex::frame::FrameDecoder<Eof<R>>>);
+
+#[cfg(feature = "lz4")]
+impl<R: Read> Read for Lz4Frames<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let decoder = self.0.as_mut().expect("no LZ4 decoder");
+            let len = decoder.read(out)?;
+            if len > 0 || out.is_empty() || decoder.get_ref().eof {
+                return Ok(len);
+            }
+            // the end of a frame while the input goes on, the next frame starts right after it
+            let reader = self.0.take().expect("no LZ4 decoder").into_inner();
+            self.0 = Some(lz4_flex::frame::FrameDecoder::new(reader));
+        }
+    }
+}
+
+/// A reader remembering whether it reached its end
+#[cfg(feature = "lz4")]
+struct Eof<R> {
+    reader: R,
+    eof: bool,
+}
+
+#[cfg(feature = "lz4")]
+impl<R: Read> Read for Eof<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(out)?;
+        self.eof |= len == 0 && !out.is_empty();
+        Ok(len)
+    }
+}
+
+/// The decompressed bytes of independent frames, decoded in parallel by rounds of about
+/// `ROUND_SIZE` compressed bytes per thread
+struct Frames<'a> {
+    buf: &'a [u8],
+    compression: Compression,
+    frames: VecDeque<Range<usize>>,
+    threads: usize,
+    /// The decoded bytes of the current round, in order
+    parts: VecDeque<Vec<u8>>,
+    /// Offset of the next byte to read in the first part
+    offset: usize,
+}
+
+impl Frames<'_> {
+    /// Decodes the next frames, returns `false` once they are all decoded
+    fn decode_round(&mut self) -> io::Result<bool> {
+        if self.frames.is_empty() {
+            return Ok(false);
+        }
+        // the compression ratio is not known, so the round is sized on the compressed bytes
+        let mut groups = Vec::with_capacity(self.threads);
+        for _ in 0..self.threads {
+            let mut group = Vec::new();
+            let mut size = 0;
+            while size < ROUND_SIZE {
+                let Some(frame) = self.frames.pop_front() else {
+                    break;
+                };
+                size += frame.len();
+                group.push(frame);
+            }
+            groups.push(group);
+        }
+
+        let (buf, compression) = (self.buf, self.compression);
+        let parts = std::thread::scope(|ctx| {
+            let handles: Vec<_> = groups
+                .into_iter()
+                .filter(|group| !group.is_empty())
+                .map(|group| {
+                    ctx.spawn(move || {
+                        let mut out = Vec::new();
+                        for frame in group {
+                            out.extend(compression.decode_frame(&buf[frame])?);
+                        }
+                        Ok(out)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .map_err(|err| io::Error::other(format!("decoder panicked ({err:?})")))?
+                })
+                .collect::<io::Result<VecDeque<_>>>()
+        })?;
+        self.parts = parts;
+        self.offset = 0;
+        Ok(true)
+    }
+}
+
+impl Read for Frames<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.parts.front() {
+                Some(part) if self.offset < part.len() => {
+                    let len = out.len().min(part.len() - self.offset);
+                    out[..len].copy_from_slice(&part[self.offset..self.offset + len]);
+                    self.offset += len;
+                    return Ok(len);
+                }
+                Some(_) => {
+                    self.parts.pop_front();
+                    self.offset = 0;
+                }
+                None => {
+                    if !self.decode_round()? {
+                        return Ok(0);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the size of the BGZF block at the start of `buf`, ie. a gzip member whose extra field
+/// holds its size as a `BC` subfield
+#[cfg(feature = "gzip")]
+fn bgzf_block_len(buf: &[u8]) -> Option<usize> {
+    const FEXTRA: u8 = 1 << 2;
+    let [0x1f, 0x8b, 8, flags, ..] = *buf else {
+        return None;
+    };
+    if flags & FEXTRA == 0 {
+        return None;
+    }
+    let xlen = u16::from_le_bytes(buf.get(10..12)?.try_into().ok()?) as usize;
+    let mut extra = buf.get(12..12 + xlen)?;
+    while let [id1, id2, len0, len1, rest @ ..] = extra {
+        let len = u16::from_le_bytes([*len0, *len1]) as usize;
+        if (*id1, *id2, len) == (b'B', b'C', 2) {
+            let size = u16::from_le_bytes(rest.get(..2)?.try_into().ok()?) as usize + 1;
+            return (size <= buf.len()).then_some(size);
+        }
+        extra = rest.get(len..)?;
+    }
+    None
+}
+
+#[cfg(all(test, any(feature = "gzip", feature = "zstd", feature = "lz4")))]
+mod tests {
+    #[cfg(any(feature = "gzip", feature = "lz4"))]
+    use std::io::Write;
+
+    use super::*;
+    use crate::Aggregator;
+
+    /// `count` records of a few stations
+    fn records(count: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..count {
+            let temp = (i * 7919 % 1999) as i64 - 999;
+            let sign = if temp < 0 { "-" } else { "" };
+            let abs = temp.unsigned_abs();
+            data.extend(format!("s{};{sign}{}.{}\n", i % 31, abs / 10, abs % 10).bytes());
+        }
+        data
+    }
+
+    /// `data` cut every `size` bytes, which cuts records across the parts
+    fn parts(data: &[u8], size: usize) -> std::slice::Chunks<'_, u8> {
+        assert!(data.chunks(size).any(|part| !part.ends_with(b"\n")));
+        data.chunks(size)
+    }
+
+    /// Checks that the compressed `input` decodes to `data` and aggregates like it, on one thread
+    /// and on several
+    fn assert_decodes(data: &[u8], input: &[u8], compression: Compression) {
+        let plain = format!("{:?}", Aggregator::new().run(data).unwrap().into_vec());
+        for threads in [1, 2, 4] {
+            let mut decoded = Vec::new();
+            let mut reader = decompress(input, compression, threads).unwrap();
+            reader.read_to_end(&mut decoded).unwrap();
+            assert!(decoded == data, "{threads} threads");
+
+            let aggregator = Aggregator::new().threads(threads).chunk_size(1 << 12);
+            let results = aggregator.run_compressed(input, compression).unwrap();
+            assert_eq!(
+                format!("{:?}", results.into_vec()),
+                plain,
+                "{threads} threads"
+            );
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// A BGZF block, ie. a gzip member with its size in a `BC` extra subfield
+    #[cfg(feature = "gzip")]
+    fn bgzf_block(data: &[u8]) -> Vec<u8> {
+        let builder = flate2::GzBuilder::new().extra(vec![b'B', b'C', 2, 0, 0, 0]);
+        let mut encoder = builder.write(Vec::new(), Default::default());
+        encoder.write_all(data).unwrap();
+        let mut block = encoder.finish().unwrap();
+        let size = u16::try_from(block.len() - 1).unwrap();
+        block[16..18].copy_from_slice(&size.to_le_bytes());
+        block
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn gzip_members() {
+        let data = records(50_000);
+        assert_decodes(&data, &gzip(&data), Compression::Gzip);
+        assert_eq!(Compression::Gzip.frames(&gzip(&data)), None);
+
+        // concatenated members, eg. appended to a log, are decoded sequentially
+        let input: Vec<u8> = parts(&data, 100_001).flat_map(gzip).collect();
+        assert_decodes(&data, &input, Compression::Gzip);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn bgzf_blocks() {
+        let data = records(300_000);
+        let input: Vec<u8> = parts(&data, 65_000).flat_map(bgzf_block).collect();
+        let frames = Compression::Gzip.frames(&input).unwrap();
+        assert_eq!(frames.len(), data.len().div_ceil(65_000));
+        assert_decodes(&data, &input, Compression::Gzip);
+
+        // the last block of bgzip is an empty one
+        let mut input = input;
+        input.extend(bgzf_block(b""));
+        assert_decodes(&data, &input, Compression::Gzip);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn zstd_frames() {
+        let data = records(300_000);
+        let whole = zstd::encode_all(&data[..], 3).unwrap();
+        assert_eq!(Compression::Zstd.frames(&whole).unwrap().len(), 1);
+        assert_decodes(&data, &whole, Compression::Zstd);
+
+        let input: Vec<u8> = parts(&data, 40_003)
+            .flat_map(|part| zstd::encode_all(part, 3).unwrap())
+            .collect();
+        assert!(Compression::Zstd.frames(&input).unwrap().len() > 1);
+        assert_decodes(&data, &input, Compression::Zstd);
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn lz4_frames() {
+        let data = records(50_000);
+        let input: Vec<u8> = parts(&data, 30_001)
+            .flat_map(|part| {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                encoder.write_all(part).unwrap();
+                encoder.finish().unwrap()
+            })
+            .collect();
+        assert_decodes(&data, &input, Compression::Lz4);
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn lz4_frames_of_different_block_sizes() {
+        use lz4_flex::frame::{BlockSize, FrameEncoder, FrameInfo};
+
+        let data = records(400_000);
+        let split = data.len() - 4000;
+        let mut input = Vec::new();
+        for (part, block_size) in [
+            (&data[..split], BlockSize::Max4MB),
+            (&data[split..], BlockSize::Max64KB),
+        ] {
+            let info = FrameInfo::new().block_size(block_size);
+            let mut encoder = FrameEncoder::with_frame_info(info, Vec::new());
+            encoder.write_all(part).unwrap();
+            input.extend(encoder.finish().unwrap());
+        }
+        assert!(data.len() > 3 << 20);
+        assert_decodes(&data, &input, Compression::Lz4);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn corrupt_frames() {
+        let data = records(10_000);
+        let mut input: Vec<u8> = parts(&data, 20_001).flat_map(bgzf_block).collect();
+        let last = input.len() - 10;
+        input[last] ^= 0xff;
+        for threads in [1, 4] {
+            let aggregator = Aggregator::new().threads(threads);
+            assert!(aggregator
+                .run_compressed(&input, Compression::Gzip)
+                .is_err());
+        }
+    }
+}
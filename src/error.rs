@@ -0,0 +1,197 @@
+use std::fmt;
+use std::ops::Range;
+
+/// A record that could not be parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// Index of the input the record belongs to, see
+    /// [`Aggregator::run_all`](crate::Aggregator::run_all)
+    pub file: usize,
+    /// Absolute byte offset of the record in the input
+    pub offset: usize,
+    /// 1-based number of the line the record starts on
+    pub line: usize,
+    /// The raw record, without its terminator
+    pub record: Vec<u8>,
+    /// The station name of the record, unquoted
+    pub station: String,
+    /// Position of the offending bytes within `record`
+    pub span: Range<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ParseErrorKind {
+    /// The record has no field delimiter
+    MissingDelimiter,
+    /// The station name is empty
+    EmptyName,
+    /// The temperature is not a number with exactly one decimal digit
+    InvalidTemperature,
+    /// The temperature is not within [-99.9, 99.9]
+    OutOfRange,
+    /// The station name is not valid UTF-8
+    InvalidUtf8,
+    /// The input ends within a quoted field
+    Truncated,
+}
+
+impl ParseErrorKind {
+    pub const ALL: [ParseErrorKind; 6] = [
+        ParseErrorKind::MissingDelimiter,
+        ParseErrorKind::EmptyName,
+        ParseErrorKind::InvalidTemperature,
+        ParseErrorKind::OutOfRange,
+        ParseErrorKind::InvalidUtf8,
+        ParseErrorKind::Truncated,
+    ];
+
+    /// A short identifier, eg. for the reject file
+    pub fn name(&self) -> &'static str {
+        match self {
+            ParseErrorKind::MissingDelimiter => "missing-delimiter",
+            ParseErrorKind::EmptyName => "empty-name",
+            ParseErrorKind::InvalidTemperature => "invalid-temperature",
+            ParseErrorKind::OutOfRange => "out-of-range",
+            ParseErrorKind::InvalidUtf8 => "invalid-utf8",
+            ParseErrorKind::Truncated => "truncated",
+        }
+    }
+}
+
+/// What to do with the records that cannot be parsed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnError {
+    /// Stop at the first one with a [`ParseError`]
+    #[default]
+    Fail,
+    /// Count them per [`ParseErrorKind`] and move on
+    Skip,
+    /// Like `Skip`, but also keep them (see [`Rejects::records`])
+    Quarantine,
+}
+
+/// The records skipped by [`OnError::Skip`] and [`OnError::Quarantine`]
+#[derive(Clone, Debug, Default)]
+pub struct Rejects {
+    counts: [usize; ParseErrorKind::ALL.len()],
+    records: Vec<Reject>,
+}
+
+/// A record kept by [`OnError::Quarantine`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reject {
+    pub kind: ParseErrorKind,
+    /// Absolute byte offset of the record in the input
+    pub offset: usize,
+    /// The raw record, without its terminator
+    pub record: Vec<u8>,
+}
+
+impl Rejects {
+    /// The number of records skipped because of `kind`
+    pub fn count(&self, kind: ParseErrorKind) -> usize {
+        self.counts[kind as usize]
+    }
+
+    /// The number of records skipped
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total() == 0
+    }
+
+    /// The quarantined records, in input order
+    pub fn records(&self) -> &[Reject] {
+        &self.records
+    }
+
+    pub(crate) fn add(&mut self, kind: ParseErrorKind, reject: Option<Reject>) {
+        self.counts[kind as usize] += 1;
+        self.records.extend(reject);
+    }
+
+    /// Adds `count` records skipped because of `kind`, without keeping them
+    pub(crate) fn add_count(&mut self, kind: ParseErrorKind, count: usize) {
+        self.counts[kind as usize] += count;
+    }
+
+    /// Puts the quarantined records back in input order
+    pub(crate) fn sort(&mut self) {
+        self.records.sort_by_key(|reject| reject.offset);
+    }
+
+    pub(crate) fn merge(&mut self, other: Rejects) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts) {
+            *count += other;
+        }
+        self.records.extend(other.records);
+    }
+}
+
+// eg. `3 records skipped (2 invalid-temperature, 1 truncated)`
+impl fmt::Display for Rejects {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} records skipped", self.total())?;
+        let mut sep = " (";
+        for kind in ParseErrorKind::ALL {
+            if self.count(kind) > 0 {
+                write!(f, "{sep}{} {}", self.count(kind), kind.name())?;
+                sep = ", ";
+            }
+        }
+        if sep == ", " {
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
+
+impl ParseError {
+    /// Computes `line` now that the whole input `buf` is known
+    pub(crate) fn locate(self, buf: &[u8]) -> Self {
+        self.locate_in(buf, 0, 1)
+    }
+
+    /// Computes `line` from the part of the input `data`, which starts at `offset` on `line`
+    pub(crate) fn locate_in(mut self, data: &[u8], offset: usize, line: usize) -> Self {
+        self.line = line
+            + data[..self.offset - offset]
+                .iter()
+                .filter(|&&b| b == b'\n')
+                .count();
+        self
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::MissingDelimiter => f.write_str("missing field delimiter"),
+            ParseErrorKind::EmptyName => f.write_str("empty station name"),
+            ParseErrorKind::InvalidTemperature => f.write_str("unable to parse temperature"),
+            ParseErrorKind::OutOfRange => f.write_str("temperature out of [-99.9, 99.9]"),
+            ParseErrorKind::InvalidUtf8 => f.write_str("station name is not valid UTF-8"),
+            ParseErrorKind::Truncated => f.write_str("unterminated quoted field"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:?} of station {:?} at line {} (byte offset {})",
+            self.kind,
+            String::from_utf8_lossy(self.record.get(self.span.clone()).unwrap_or_default()),
+            self.station,
+            self.line,
+            self.offset
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
@@ -0,0 +1,154 @@
+use anyhow::{bail, Result};
+
+use crate::chunk::{Quotes, Quoting};
+
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// How the records of an input are laid out.
+///
+/// Defaults to the 1BRC layout: `<station>;<temperature>` records terminated by LF, without header
+/// nor comments. A leading UTF-8 BOM is always skipped. The temperature is the last field, so that
+/// the station name is all the record before it, delimiters included.
+///
+/// ```
+/// use chunkit::{Format, LineEnding};
+///
+/// let csv = Format::new()
+///     .delimiter(b',')
+///     .line_ending(LineEnding::CrLf)
+///     .header(true)
+///     .comment(Some(b'#'));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Format {
+    pub(crate) delimiter: u8,
+    pub(crate) line_ending: LineEnding,
+    pub(crate) header: bool,
+    pub(crate) comment: Option<u8>,
+    pub(crate) utf8: Utf8,
+}
+
+/// How station names that are not valid UTF-8 are handled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Utf8 {
+    /// The record is invalid, see [`ParseErrorKind::InvalidUtf8`](crate::ParseErrorKind)
+    #[default]
+    Reject,
+    /// The invalid sequences are replaced with U+FFFD
+    Lossy,
+}
+
+/// The terminator of the records
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    /// A CR right before the LF is not part of the temperature
+    CrLf,
+}
+
+impl Format {
+    pub fn new() -> Self {
+        Self {
+            delimiter: b';',
+            line_ending: LineEnding::Lf,
+            header: false,
+            comment: None,
+            utf8: Utf8::Reject,
+        }
+    }
+
+    /// Sets the byte separating the station name from the temperature (eg. `;`, `,`, `\t` or `|`)
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    /// Whether the first record is a header to skip
+    pub fn header(mut self, header: bool) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Records starting with this byte are skipped.
+    ///
+    /// Like any other record, a comment ends at the first LF that is not within quotes.
+    pub fn comment(mut self, comment: Option<u8>) -> Self {
+        self.comment = comment;
+        self
+    }
+
+    pub fn utf8(mut self, utf8: Utf8) -> Self {
+        self.utf8 = utf8;
+        self
+    }
+
+    /// Whether `byte` can be mistaken for the rest of the syntax, so that it is neither a valid
+    /// delimiter nor a valid comment byte: quotes, line endings, the chars of the temperatures
+    /// and the non-ASCII bytes
+    pub fn is_reserved(byte: u8) -> bool {
+        matches!(byte, b'"' | b'\n' | b'\r' | b'-' | b'.')
+            || byte.is_ascii_digit()
+            || !byte.is_ascii()
+    }
+
+    /// Makes sure the delimiter and comment bytes cannot be mistaken for the rest of the syntax
+    pub(crate) fn check(&self) -> Result<()> {
+        if Self::is_reserved(self.delimiter) {
+            bail!("invalid delimiter {:?}", self.delimiter as char);
+        }
+        if let Some(comment) = self.comment {
+            if Self::is_reserved(comment) || comment == self.delimiter {
+                bail!("invalid comment char {:?}", comment as char);
+            }
+        }
+        Ok(())
+    }
+
+    /// Length of the UTF-8 BOM `buf` starts with, if any
+    pub(crate) fn bom_len(buf: &[u8]) -> usize {
+        if buf.starts_with(BOM) {
+            BOM.len()
+        } else {
+            0
+        }
+    }
+
+    /// Offset of the first record, past the BOM and the header
+    pub(crate) fn body_start(&self, buf: &[u8]) -> usize {
+        let mut start = Self::bom_len(buf);
+        if self.header {
+            let quotes = Quotes::new(buf, start, self.delimiter);
+            start = match quotes.record_end(start, Quoting::Unquoted) {
+                Some(lf) => lf + 1,
+                None => buf.len(),
+            };
+        }
+        start
+    }
+
+    /// Strips the CR of a CRLF terminated `value`
+    #[inline]
+    pub(crate) fn trim_value<'a>(&self, value: &'a [u8]) -> &'a [u8] {
+        match (self.line_ending, value) {
+            (LineEnding::CrLf, [value @ .., b'\r']) => value,
+            _ => value,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_comment(&self, record: &[u8]) -> bool {
+        matches!((self.comment, record.first()), (Some(comment), Some(&first)) if comment == first)
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,134 @@
+/// The lowest temperature, in tenths of degree
+const MIN_TEMP: i16 = -999;
+/// The number of temperatures in [-99.9, 99.9]
+const BUCKETS: usize = 1999;
+/// Buckets per page, the pages are only allocated once one of their buckets is hit
+const PAGE: usize = 64;
+const PAGES: usize = BUCKETS.div_ceil(PAGE);
+
+/// The exact number of readings of each temperature of a station.
+///
+/// Temperatures have one decimal and lie in [-99.9, 99.9], so a bucket per temperature is enough
+/// to know every reading. The buckets are grouped in pages allocated on first use, so that a
+/// station whose readings span a few tens of degrees only takes a few KiB, whatever the number
+/// of readings.
+///
+/// The buckets count up to `u32::MAX`, the rare overflows are carried in a side list.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Histogram {
+    pages: [Option<Box<[u32; PAGE]>>; PAGES],
+    /// The number of times a bucket wrapped around, by bucket index
+    carries: Vec<(u16, u32)>,
+}
+
+impl Histogram {
+    /// Records the reading `temp`, in tenths of degree within [-999, 999]
+    #[inline]
+    pub(crate) fn add(&mut self, temp: i16) {
+        debug_assert!(
+            (-999..=999).contains(&temp),
+            "temperature {temp} out of range"
+        );
+        self.add_count((temp - MIN_TEMP) as usize, 1);
+    }
+
+    pub(crate) fn merge(&mut self, other: &Histogram) {
+        for (page, counts) in other.pages.iter().enumerate() {
+            for (offset, &count) in counts.iter().flat_map(|counts| counts.iter().enumerate()) {
+                if count > 0 {
+                    self.add_count(page * PAGE + offset, count);
+                }
+            }
+        }
+        for &(bucket, wraps) in &other.carries {
+            self.carry(bucket, wraps);
+        }
+    }
+
+    /// Iterates over the temperatures seen, in increasing order, with their number of readings
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (i16, u64)> + '_ {
+        self.pages
+            .iter()
+            .enumerate()
+            .flat_map(|(page, counts)| {
+                counts.iter().flat_map(move |counts| {
+                    counts
+                        .iter()
+                        .enumerate()
+                        .map(move |(offset, &count)| (page * PAGE + offset, count))
+                })
+            })
+            .map(|(bucket, count)| {
+                let wraps = match self.carries.is_empty() {
+                    true => 0,
+                    false => self
+                        .carries
+                        .iter()
+                        .find(|(carried, _)| *carried as usize == bucket)
+                        .map_or(0, |&(_, wraps)| wraps),
+                };
+                let count = count as u64 + ((wraps as u64) << 32);
+                (bucket as i16 + MIN_TEMP, count)
+            })
+            .filter(|&(_, count)| count > 0)
+    }
+
+    /// Adds `count` readings of the temperature `temp`, eg. read from a partial aggregate
+    pub(crate) fn add_temp_count(&mut self, temp: i16, count: u64) {
+        let bucket = (temp - MIN_TEMP) as usize;
+        self.add_count(bucket, count as u32);
+        self.carry(bucket as u16, (count >> 32) as u32);
+    }
+
+    /// The `rank`-th smallest reading (0-based), the histogram holding more readings than `rank`
+    fn nth(&self, rank: u64) -> Option<i16> {
+        let mut seen = 0;
+        self.iter().find_map(|(temp, count)| {
+            seen += count;
+            (seen > rank).then_some(temp)
+        })
+    }
+
+    /// The `q`-quantile of the `count` readings (`q` in [0, 1]), in tenths of degree.
+    ///
+    /// The quantile is interpolated linearly between the two closest ranks, as most tools do by
+    /// default, so that the median of an even number of readings is the mean of the middle ones.
+    pub(crate) fn quantile(&self, count: usize, q: f64) -> Option<f64> {
+        if count == 0 || !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+        let rank = (count - 1) as f64 * q;
+        let low = rank.floor();
+        let below = self.nth(low as u64)? as f64;
+        if rank == low {
+            return Some(below);
+        }
+        let above = self.nth(low as u64 + 1)? as f64;
+        Some(below + (rank - low) * (above - below))
+    }
+
+    #[inline]
+    fn add_count(&mut self, bucket: usize, count: u32) {
+        let counts = self.pages[bucket / PAGE].get_or_insert_with(|| Box::new([0; PAGE]));
+        let (sum, overflow) = counts[bucket % PAGE].overflowing_add(count);
+        counts[bucket % PAGE] = sum;
+        if overflow {
+            self.carry(bucket as u16, 1);
+        }
+    }
+
+    #[cold]
+    fn carry(&mut self, bucket: u16, wraps: u32) {
+        if wraps == 0 {
+            return;
+        }
+        match self
+            .carries
+            .iter_mut()
+            .find(|(carried, _)| *carried == bucket)
+        {
+            Some((_, carried)) => *carried += wraps,
+            None => self.carries.push((bucket, wraps)),
+        }
+    }
+}
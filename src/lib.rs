@@ -0,0 +1,31 @@
+//! Multi-threaded aggregation of `<station>;<temperature>` measurements into
+//! min/mean/max per station, as defined by the One Billion Row Challenge.
+//!
+//! Other delimiters, CRLF line endings, headers and comments are supported through [`Format`].
+
+mod aggregator;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+mod chunk;
+pub mod decode;
+mod error;
+mod format;
+mod histogram;
+mod moments;
+pub mod output;
+pub mod partial;
+mod rounding;
+mod scan;
+mod sensor;
+mod sketch;
+mod stream;
+mod table;
+
+pub use aggregator::{Aggregator, Results};
+pub use chunk::{chunk_it, Chunk};
+pub use error::{OnError, ParseError, ParseErrorKind, Reject, Rejects};
+pub use format::{Format, LineEnding, Utf8};
+pub use rounding::Rounding;
+pub use sensor::Sensor;
+
+pub type Symbol = String;
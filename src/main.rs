@@ -1,268 +1,1010 @@
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::thread::ScopedJoinHandle;
+use std::io::{BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
-use anyhow::{anyhow, Context, Error, Result};
-use memmap2::MmapOptions;
-
-type Symbol = String;
-type HashMap = ahash::AHashMap<Symbol, Sensor>;
+use anyhow::{bail, Context, Result};
+use chunkit::decode::{self, Compression};
+use chunkit::output::{self, Csv, Output};
+use chunkit::partial::{self, Partial};
+use chunkit::{Aggregator, Format, LineEnding, OnError, ParseError, Results, Rounding, Utf8};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use memmap2::{Mmap, MmapOptions};
+
+/// Aggregates `<station>;<temperature>` measurements into min/mean/max per station
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about,
+    override_usage = "chunkit [OPTIONS] <INPUT>...\n       chunkit [-q] <COMMAND>",
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    aggregate: AggregateArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+
+    /// Do not print timings on stderr
+    #[arg(short, long, global = true)]
+    quiet: bool,
+}
 
-fn main() -> Result<()> {
-    let nb_threads = num_cpus::get();
-    let file = File::open("../measurements.txt").context("unable to open file")?;
-    let mmap = unsafe {
-        MmapOptions::new()
-            .map(&file)
-            .context("unable to mmap the file")?
-    };
-    // mmap.advise(memmap2::Advice::Sequential)
-    //     .context("unable to set mmap advice sequential")?;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Aggregates the inputs into a partial aggregate, to be merged with others by `combine`.
+    ///
+    /// The partial aggregate keeps the exact state of every station, so that shards aggregated
+    /// on different machines are merged without any rounding
+    Partial {
+        #[command(flatten)]
+        aggregate: AggregateArgs,
+
+        /// Where to write the partial aggregate, "-" or nothing means stdout
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Merges the partial aggregates written by `partial` and writes their results
+    Combine {
+        /// Partial aggregates to merge, with the same wildcards as the inputs. "-" reads stdin
+        #[arg(required = true, value_name = "PARTIAL")]
+        partials: Vec<PathBuf>,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+}
 
-    let chunks = chunk_it(&mmap, nb_threads).context("unable to chunk the file")?;
-    eprintln!("processing {} chunks...", chunks.len());
+/// How the inputs are read and aggregated
+#[derive(Args, Debug)]
+struct AggregateArgs {
+    /// Measurement files to aggregate, their results are merged together. The file names may
+    /// contain the `*`, `?` and `[...]` wildcards, eg. 'measurements-2026-10-*.txt'. "-" reads
+    /// stdin, pipes and other inputs that cannot be mapped in memory are streamed. gzip, zstd and
+    /// lz4 inputs are decompressed on the fly, provided the matching feature is enabled
+    #[arg(required = true, value_name = "INPUT")]
+    inputs: Vec<PathBuf>,
+
+    /// Also write the results of each input to this directory, as `<input file name>.<format>`
+    /// (`<input file name>.chkp` partial aggregates with `partial`)
+    #[arg(long, value_name = "DIR")]
+    per_file: Option<PathBuf>,
+
+    /// Number of worker threads [default: number of logical cores]
+    #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+
+    /// Number of chunks each input is split into, instead of chunks of --chunk-size
+    #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    chunks: Option<u32>,
+
+    /// Size of the chunks the worker threads pull one at a time, in MiB [default: 8]
+    #[arg(
+        long,
+        value_name = "MIB",
+        conflicts_with = "chunks",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    chunk_size: Option<u32>,
+
+    /// Byte separating the station from the temperature, eg. ',', '|' or '\t'
+    #[arg(short, long, value_name = "CHAR", default_value = ";", value_parser = parse_syntax_byte)]
+    delimiter: u8,
+
+    /// Records end with CRLF instead of LF
+    #[arg(long)]
+    crlf: bool,
+
+    /// Skip the first record of each input
+    #[arg(long)]
+    header: bool,
+
+    /// Skip the records starting with this byte, eg. '#'
+    #[arg(long, value_name = "CHAR", value_parser = parse_syntax_byte)]
+    comment: Option<u8>,
+
+    /// How to handle station names that are not valid UTF-8
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = Utf8Mode::Reject)]
+    utf8: Utf8Mode,
+
+    /// What to do with the records that cannot be parsed
+    ///
+    /// The skipped records are summed up per kind in a warning on stderr, not in the results: a
+    /// `rejects` key or row would be mistaken for a station by the JSON, NDJSON and CSV readers.
+    /// The partial aggregates keep the counts for `combine`
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = ErrorMode::Fail)]
+    on_error: ErrorMode,
+
+    /// Where to write the quarantined records, as `<input>:<offset>\t<kind>\t<record>` lines. The
+    /// backslashes, tabs, CR and LF of the records are escaped as `\\`, `\t`, `\r` and `\n`
+    #[arg(long, value_name = "PATH", required_if_eq("on_error", "quarantine"))]
+    rejects: Option<PathBuf>,
+
+    /// Also compute the variance, standard deviation, skewness and kurtosis of each station,
+    /// written by every format but text
+    #[arg(long)]
+    moments: bool,
+
+    /// Also keep the number of readings of each temperature of each station, for their exact
+    /// percentiles. Implied by --percentiles without --sketch, needed by `partial` for
+    /// `combine --percentiles`
+    #[arg(long)]
+    histograms: bool,
+
+    /// Estimate the percentiles with sketches of this relative accuracy, eg. 0.01 for 1%, instead
+    /// of the histograms
+    #[arg(long, value_name = "ACCURACY", value_parser = parse_accuracy)]
+    sketch: Option<f64>,
+}
 
-    let start = Instant::now();
-    let sensors = process_chunks(&chunks)?;
-    eprintln!("processing time {:?}", start.elapsed());
+/// How the results are written
+#[derive(Args, Debug)]
+struct OutputArgs {
+    /// Where to write the results, "-" or nothing means stdout
+    #[arg(short = 'o', long = "output", value_name = "PATH")]
+    path: Option<PathBuf>,
+
+    /// Output format of the results
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Number of decimals of the JSON, CSV and TSV floats, "shortest" writes them as short as
+    /// possible
+    #[arg(long, value_name = "N", default_value = "1", value_parser = parse_precision)]
+    precision: Precision,
+
+    /// How the min, mean and max are rounded to their number of decimals
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = RoundingMode::CeilHalf)]
+    rounding: RoundingMode,
+
+    /// Decimal separator of the CSV and TSV floats
+    #[arg(long, value_name = "CHAR", default_value = ".", value_parser = parse_byte)]
+    decimal: u8,
+
+    /// Also write these percentiles of each station, eg. 50,90,99, with every format but text.
+    /// Exact with the histograms, estimated with --sketch
+    #[arg(long, value_name = "LIST", value_delimiter = ',', value_parser = parse_percentile)]
+    percentiles: Vec<f64>,
+}
 
-    let start = Instant::now();
-    let sensors = merge_results(sensors);
-    eprintln!("merge took {:?}", start.elapsed());
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum OutputFormat {
+    /// The 1BRC layout: `{name=min/mean/max, ...}`
+    Text,
+    /// A JSON object keyed by station: `{"name":{"min":..,"avg":..,"max":..,"count":..},...}`
+    Json,
+    /// One JSON object per line: `{"station":"name","min":..,"avg":..,"max":..,"count":..}`
+    Ndjson,
+    /// Comma separated values with a `station,min,mean,max,count` header
+    Csv,
+    /// Tab separated values with a `station\tmin\tmean\tmax\tcount` header
+    Tsv,
+    /// An Arrow IPC file, requires the `arrow` feature
+    #[cfg(feature = "arrow")]
+    Arrow,
+    /// A Parquet file, requires the `arrow` feature
+    #[cfg(feature = "arrow")]
+    Parquet,
+}
 
-    let start = Instant::now();
-    write_results(sensors, "results.txt")?;
-    eprintln!("writing result took {:?}", start.elapsed());
+impl OutputArgs {
+    /// The writer of the results
+    fn build(&self) -> Box<dyn Output> {
+        let precision = self.precision.0;
+        let percentiles = self.percentiles.clone();
+        let rounding = match self.rounding {
+            RoundingMode::CeilHalf => Rounding::CeilHalf,
+            RoundingMode::HalfEven => Rounding::HalfEven,
+            RoundingMode::HalfAway => Rounding::HalfAwayFromZero,
+        };
+        match self.format {
+            OutputFormat::Text => Box::new(output::Text { rounding }),
+            OutputFormat::Json => Box::new(output::Json {
+                precision,
+                rounding,
+                percentiles,
+            }),
+            OutputFormat::Ndjson => Box::new(output::Ndjson {
+                precision,
+                rounding,
+                percentiles,
+            }),
+            OutputFormat::Csv => Box::new(
+                Csv::new()
+                    .decimal(self.decimal)
+                    .precision(precision)
+                    .rounding(rounding)
+                    .percentiles(percentiles),
+            ),
+            OutputFormat::Tsv => Box::new(
+                Csv::tsv()
+                    .decimal(self.decimal)
+                    .precision(precision)
+                    .rounding(rounding)
+                    .percentiles(percentiles),
+            ),
+            #[cfg(feature = "arrow")]
+            OutputFormat::Arrow => Box::new(chunkit::arrow::Ipc { percentiles }),
+            #[cfg(feature = "arrow")]
+            OutputFormat::Parquet => Box::new(chunkit::arrow::Parquet { percentiles }),
+        }
+    }
+}
 
-    Ok(())
+impl OutputFormat {
+    /// The extension of the files written in this format
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Text => "txt",
+            OutputFormat::Json => "json",
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Tsv => "tsv",
+            #[cfg(feature = "arrow")]
+            OutputFormat::Arrow => "arrow",
+            #[cfg(feature = "arrow")]
+            OutputFormat::Parquet => "parquet",
+        }
+    }
 }
 
-// TODO make sure we are not in an escaped LF when trying to find('\n')
-// cause right now that's a bug
-pub fn chunk_it(buf: &[u8], nb_chunks: usize) -> Result<Vec<Chunk<'_>>, Error> {
-    let data = unsafe { std::str::from_utf8_unchecked(buf) };
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum RoundingMode {
+    /// Ties go toward positive infinity, as mandated by the 1BRC rules
+    CeilHalf,
+    /// Ties go to the even neighbour
+    HalfEven,
+    /// Ties go away from zero
+    HalfAway,
+}
 
-    let eof = data.len();
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Utf8Mode {
+    /// The record is invalid, see --on-error
+    Reject,
+    /// Replace the invalid sequences with U+FFFD
+    Lossy,
+}
 
-    let mut chunks = Vec::with_capacity(nb_chunks);
-    let chunk_size = eof / nb_chunks;
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ErrorMode {
+    /// Stop at the first invalid record
+    Fail,
+    /// Skip the invalid records, counting them per kind
+    Skip,
+    /// Skip the invalid records and write them to the rejects file
+    Quarantine,
+}
 
-    let mut offset = 0;
-    for _ in 0..nb_chunks {
-        let mut end = offset + chunk_size;
-        // if after eof then, eof
-        if end >= eof {
-            end = eof;
+fn main() -> Result<()> {
+    let mut cli = parse_cli(std::env::args_os()).unwrap_or_else(|err| err.exit());
+    cli.aggregate.histograms |=
+        !cli.output.percentiles.is_empty() && cli.aggregate.sketch.is_none();
+    match &cli.command {
+        None => {
+            let output = cli.output.build();
+            let extension = cli.output.format.extension();
+            let results = aggregate(&cli.aggregate, &*output, extension, cli.quiet)?;
+            write(&results, cli.output.path.as_deref(), &*output, cli.quiet)
         }
-        if offset == end {
-            break;
+        Some(Command::Partial {
+            aggregate: args,
+            output,
+        }) => {
+            let results = aggregate(args, &Partial, "chkp", cli.quiet)?;
+            write(&results, output.as_deref(), &Partial, cli.quiet)
         }
-        // else, try to find the closest LF char
-        match data[end..].find('\n') {
-            Some(lf) => {
-                end += lf + 1;
-                chunks.push(Chunk {
-                    data: &buf[offset..end],
-                    start: offset,
-                    end,
-                });
-                offset = end;
+        Some(Command::Combine { partials, output }) => {
+            let results = combine(partials)?;
+            let percentiles = results.has_histograms() || results.has_sketches();
+            if !output.percentiles.is_empty() && !results.is_empty() && !percentiles {
+                bail!(
+                    "the percentiles need partial aggregates written with --histograms or --sketch"
+                );
             }
-            None => {
-                chunks.push(Chunk {
-                    data: &buf[offset..end],
-                    start: offset,
-                    end: eof,
-                });
-                break;
+            write(
+                &results,
+                output.path.as_deref(),
+                &*output.build(),
+                cli.quiet,
+            )
+        }
+    }
+}
+
+/// Parses the command line, the top-level arguments but --quiet conflicting with the subcommands,
+/// and --comment with the delimiter.
+///
+/// clap's `args_conflicts_with_subcommands` would reject `chunkit -q partial ...` as well, since
+/// it does not spare the global arguments.
+fn parse_cli(args: impl IntoIterator<Item = OsString>) -> Result<Cli, clap::Error> {
+    let matches = Cli::command().try_get_matches_from(args)?;
+    if let Some((name, _)) = matches.subcommand() {
+        let mut command = Cli::command();
+        command.build();
+        let conflict = command.get_arguments().find(|arg| {
+            !arg.is_global_set()
+                && matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+        });
+        if let Some(arg) = conflict {
+            let message = format!("the argument '{arg}' cannot be used with '{name}'");
+            return Err(command.error(ErrorKind::ArgumentConflict, message));
+        }
+    }
+    let cli = Cli::from_arg_matches(&matches)?;
+    let aggregate = match &cli.command {
+        None => Some(&cli.aggregate),
+        Some(Command::Partial { aggregate, .. }) => Some(aggregate),
+        Some(Command::Combine { .. }) => None,
+    };
+    if let Some(args) = aggregate.filter(|args| args.comment == Some(args.delimiter)) {
+        let message = format!(
+            "the comment char '{}' cannot be the delimiter",
+            args.delimiter.escape_ascii()
+        );
+        return Err(Cli::command().error(ErrorKind::ArgumentConflict, message));
+    }
+    Ok(cli)
+}
+
+/// Aggregates the inputs of `args`, writing the results of each one with `output` when asked to
+fn aggregate(
+    args: &AggregateArgs,
+    output: &dyn Output,
+    extension: &str,
+    quiet: bool,
+) -> Result<Results> {
+    let mut inputs = Vec::with_capacity(args.inputs.len());
+    for pattern in &args.inputs {
+        let matches =
+            expand(pattern).with_context(|| format!("unable to expand '{}'", pattern.display()))?;
+        if matches.is_empty() {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("no input matches '{}'", pattern.display()),
+                )
+                .exit();
+        }
+        inputs.extend(matches);
+    }
+    for input in &inputs {
+        if input.as_os_str() != "-" && (!input.exists() || input.is_dir()) {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("input '{}' is not a readable file", input.display()),
+                )
+                .exit();
+        }
+    }
+    let per_file = match &args.per_file {
+        Some(dir) => Some(per_file_paths(dir, &inputs, extension)?),
+        None => None,
+    };
+
+    let format = Format::new()
+        .delimiter(args.delimiter)
+        .line_ending(if args.crlf {
+            LineEnding::CrLf
+        } else {
+            LineEnding::Lf
+        })
+        .header(args.header)
+        .comment(args.comment)
+        .utf8(match args.utf8 {
+            Utf8Mode::Reject => Utf8::Reject,
+            Utf8Mode::Lossy => Utf8::Lossy,
+        });
+    let on_error = match args.on_error {
+        ErrorMode::Fail => OnError::Fail,
+        ErrorMode::Skip => OnError::Skip,
+        ErrorMode::Quarantine => OnError::Quarantine,
+    };
+    let mut aggregator = Aggregator::new()
+        .format(format)
+        .on_error(on_error)
+        .moments(args.moments)
+        .histograms(args.histograms)
+        .sketches(args.sketch)
+        .verbose(!quiet);
+    let mut rejects = match &args.rejects {
+        Some(path) => {
+            Some(BufWriter::new(File::create(path).with_context(|| {
+                format!("unable to create '{}'", path.display())
+            })?))
+        }
+        None => None,
+    };
+    if let Some(threads) = args.threads {
+        aggregator = aggregator.threads(threads as usize);
+    }
+    if let Some(chunks) = args.chunks {
+        aggregator = aggregator.chunks(chunks as usize);
+    }
+    if let Some(chunk_size) = args.chunk_size {
+        aggregator = aggregator.chunk_size((chunk_size as usize) << 20);
+    }
+
+    // the uncompressed files are mapped and aggregated together, the other inputs one at a time
+    let mut partials: Vec<Option<Results>> = inputs.iter().map(|_| None).collect();
+    let mut mapped = Vec::new();
+    for (index, input) in inputs.iter().enumerate() {
+        if !quiet {
+            eprintln!("aggregating '{}'...", input.display());
+        }
+        let partial = match open(input)? {
+            Input::Mapped(mmap) => match Compression::detect(&mmap, Some(input)) {
+                Some(compression) => aggregator.run_compressed(&mmap, compression),
+                None => {
+                    mapped.push((index, mmap));
+                    continue;
+                }
+            },
+            Input::Stream(reader) => stream(&aggregator, input, reader),
+        };
+        partials[index] = Some(partial.map_err(|err| fail(input, None, err))?);
+    }
+    if !mapped.is_empty() {
+        let mmaps: Vec<&[u8]> = mapped.iter().map(|(_, mmap)| &mmap[..]).collect();
+        let results = aggregator.run_all(&mmaps).map_err(|err| {
+            match err.downcast_ref::<ParseError>().map(|err| err.file) {
+                Some(file) => fail(&inputs[mapped[file].0], Some(mmaps[file]), err),
+                None => err.context("unable to aggregate the inputs"),
             }
+        })?;
+        for ((index, _), partial) in mapped.iter().zip(results) {
+            partials[*index] = Some(partial);
         }
     }
 
-    Ok(chunks)
+    let mut results = Results::default();
+    for (index, (input, partial)) in inputs.iter().zip(partials).enumerate() {
+        let partial = partial.unwrap_or_default();
+        if let Some(writer) = &mut rejects {
+            write_rejects(input, &partial, writer).context("unable to write the rejects")?;
+        }
+        if let Some(paths) = &per_file {
+            let path = &paths[index];
+            let file = File::create(path)
+                .with_context(|| format!("unable to create '{}'", path.display()))?;
+            write_results(&partial, BufWriter::new(file), output)?;
+        }
+        results.merge(partial);
+    }
+    if let Some(mut writer) = rejects {
+        writer.flush().context("unable to write the rejects")?;
+    }
+    if !results.rejects().is_empty() {
+        eprintln!("warning: {}", results.rejects());
+    }
+
+    Ok(results)
 }
 
-fn process_chunks(chunks: &[Chunk<'_>]) -> Result<Vec<HashMap>> {
-    let sensors = std::thread::scope(move |ctx| {
-        let handles = chunks
-            .iter()
-            .map(|chunk| {
-                ctx.spawn(move || {
-                    let start = Instant::now();
-                    let tid = std::thread::current().id();
-                    let sensors = process_chunk(chunk);
-                    eprintln!("{tid:?} took {:?}", start.elapsed());
-                    sensors
-                })
-            })
-            .collect::<Vec<ScopedJoinHandle<_>>>();
-
-        handles
-            .into_iter()
-            .map(|handle| {
-                handle
-                    .join()
-                    .map_err(|err| anyhow!("unable to join the thread ({err:?})"))?
-            })
-            .collect::<Result<Vec<_>>>()
-    })?;
+/// Merges the partial aggregates matching `patterns`
+fn combine(patterns: &[PathBuf]) -> Result<Results> {
+    let mut results = Results::default();
+    for pattern in patterns {
+        let paths =
+            expand(pattern).with_context(|| format!("unable to expand '{}'", pattern.display()))?;
+        if paths.is_empty() {
+            bail!("no partial aggregate matches '{}'", pattern.display());
+        }
+        for path in paths {
+            let partial = if path.as_os_str() == "-" {
+                partial::read(&mut std::io::stdin().lock())
+            } else {
+                File::open(&path)
+                    .with_context(|| format!("unable to open '{}'", path.display()))
+                    .and_then(|mut file| partial::read(&mut file))
+            };
+            results
+                .merge(partial.with_context(|| format!("unable to combine '{}'", path.display()))?);
+        }
+    }
+    if !results.rejects().is_empty() {
+        eprintln!("warning: {}", results.rejects());
+    }
+    Ok(results)
+}
 
-    Ok(sensors)
+/// Writes the results to `path`, stdout if `None` or "-"
+fn write(results: &Results, path: Option<&Path>, output: &dyn Output, quiet: bool) -> Result<()> {
+    let start = Instant::now();
+    match path {
+        Some(path) if path.as_os_str() != "-" => {
+            let file = File::create(path)
+                .with_context(|| format!("unable to create '{}'", path.display()))?;
+            write_results(results, BufWriter::new(file), output)?;
+        }
+        _ => write_results(results, std::io::stdout().lock(), output)?,
+    }
+    if !quiet {
+        eprintln!("writing result took {:?}", start.elapsed());
+    }
+    Ok(())
 }
 
-fn process_chunk(chunk: &Chunk<'_>) -> Result<HashMap> {
-    let total = chunk.data.len();
+enum Input {
+    Mapped(Mmap),
+    Stream(Box<dyn Read>),
+}
 
-    let mut sensors = HashMap::with_capacity(450);
-    let mut name = String::with_capacity(25);
-    let mut prev = 0;
-    let mut curr = 0;
-    loop {
-        if curr == total {
-            break;
+/// Maps `path` in memory if possible, streams it otherwise (eg. stdin, a pipe or a FIFO)
+fn open(path: &Path) -> Result<Input> {
+    if path.as_os_str() == "-" {
+        return Ok(Input::Stream(Box::new(std::io::stdin().lock())));
+    }
+    let file = File::open(path).with_context(|| format!("unable to open '{}'", path.display()))?;
+    if file.metadata().is_ok_and(|metadata| metadata.is_file()) {
+        if let Ok(mmap) = unsafe { MmapOptions::new().map(&file) } {
+            // mmap.advise(memmap2::Advice::Sequential)
+            //     .context("unable to set mmap advice sequential")?;
+            return Ok(Input::Mapped(mmap));
         }
+    }
+    Ok(Input::Stream(Box::new(file)))
+}
 
-        match chunk.data[curr] {
-            b';' => {
-                let text = unsafe { std::str::from_utf8_unchecked(&chunk.data[prev..curr]) };
-                // eprintln!("name=\"{text}\"");
-                name.clear();
-                name.push_str(text);
-                curr += 1;
-                // moving on
-                prev = curr;
-            }
-            b'\n' => {
-                let text = unsafe { std::str::from_utf8_unchecked(&chunk.data[prev..curr]) };
-                let temp = text.parse::<f32>().context("unable to parse float")?;
-
-                // line completed, record it
-                sensors
-                    .entry(name.clone())
-                    .and_modify(|s| s.add_temp(temp))
-                    .or_insert_with(|| Sensor::new(temp));
-
-                // and still increment
-                curr += 1;
-                // moving on
-                prev = curr;
-            }
-            _ => {
-                curr += 1;
-            }
+/// Prints the diagnostic of a parse error and exits, or adds the input to the other errors
+fn fail(input: &Path, buf: Option<&[u8]>, err: anyhow::Error) -> anyhow::Error {
+    if let Some(err) = err.downcast_ref::<ParseError>() {
+        eprint!("{}", diagnostic(input, buf, err));
+        std::process::exit(1);
+    }
+    err.context(format!("unable to aggregate '{}'", input.display()))
+}
+
+/// Aggregates `reader`, decompressing it if its first bytes or the extension of `path` tell so
+fn stream(aggregator: &Aggregator, path: &Path, mut reader: Box<dyn Read>) -> Result<Results> {
+    let mut magic = Vec::with_capacity(4);
+    Read::take(&mut reader, 4)
+        .read_to_end(&mut magic)
+        .context("unable to read the input")?;
+    let compression = Compression::detect(&magic, Some(path));
+    let reader = Cursor::new(magic).chain(reader);
+    match compression {
+        Some(compression) => aggregator.run_reader(decode::decompress_reader(reader, compression)?),
+        None => aggregator.run_reader(reader),
+    }
+}
+
+/// Lists the files matching the wildcards of the file name of `pattern`, in name order.
+///
+/// A pattern without wildcards is returned as is, whether the file exists or not.
+fn expand(pattern: &Path) -> Result<Vec<PathBuf>> {
+    let Some(name) = pattern.file_name().and_then(|name| name.to_str()) else {
+        return Ok(vec![pattern.to_owned()]);
+    };
+    if !name.contains(['*', '?', '[']) {
+        return Ok(vec![pattern.to_owned()]);
+    }
+    let name: Vec<char> = name.chars().collect();
+    let dir = pattern.parent().unwrap_or(Path::new(""));
+    let mut paths = Vec::new();
+    let entries = std::fs::read_dir(if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    })?;
+    for entry in entries {
+        let entry = entry?;
+        let Ok(file_name) = entry.file_name().into_string() else {
+            continue;
+        };
+        // hidden files only match an explicit dot, as in shells
+        if file_name.starts_with('.') && name[0] != '.' {
+            continue;
+        }
+        let chars: Vec<char> = file_name.chars().collect();
+        if matches(&name, &chars) && !entry.path().is_dir() {
+            paths.push(dir.join(file_name));
         }
     }
+    paths.sort();
+    Ok(paths)
+}
 
-    Ok(sensors)
+/// Whether `name` matches `pattern`, with the `*`, `?`, `[abc]`, `[a-z]` and `[!abc]` wildcards
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern {
+        [] => name.is_empty(),
+        ['*', rest @ ..] => (0..=name.len()).any(|skip| matches(rest, &name[skip..])),
+        ['?', rest @ ..] => !name.is_empty() && matches(rest, &name[1..]),
+        ['[', class @ ..] => {
+            let (negated, class) = match class {
+                ['!' | '^', class @ ..] => (true, class),
+                _ => (false, class),
+            };
+            // a `]` right after the opening bracket is part of the class
+            let Some(close) = class.iter().skip(1).position(|&c| c == ']') else {
+                // no class, a plain `[`
+                return name.first() == Some(&'[') && matches(&pattern[1..], &name[1..]);
+            };
+            let (set, rest) = (&class[..close + 1], &class[close + 2..]);
+            let Some((c, name)) = name.split_first() else {
+                return false;
+            };
+            let mut found = false;
+            let mut index = 0;
+            while index < set.len() {
+                if let [low, '-', high, ..] = set[index..] {
+                    found |= (low..=high).contains(c);
+                    index += 3;
+                } else {
+                    found |= set[index] == *c;
+                    index += 1;
+                }
+            }
+            found != negated && matches(rest, name)
+        }
+        [c, rest @ ..] => name.first() == Some(c) && matches(rest, &name[1..]),
+    }
 }
 
-fn write_results(sensors: Vec<(Symbol, Sensor)>, path: &str) -> Result<()> {
-    let result = File::create(path).context("unable to create results.txt")?;
-    let mut writer = BufWriter::new(result);
-    writer.write_all(b"{")?;
-    let last_index = sensors.len() - 1;
-    for (index, (name, Sensor { min, sum, cnt, max })) in sensors.into_iter().enumerate() {
-        writer
-            .write_fmt(format_args!(
-                "{name}={min:.1}/{:.1}/{max:.1}",
-                sum / cnt as f32
-            ))
-            .context("unable to write")?;
-        if index < last_index {
-            writer.write_all(b", ")?;
+/// The files the results of each input are written to with --per-file, creating `dir` if needed
+fn per_file_paths(dir: &Path, inputs: &[PathBuf], extension: &str) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("unable to create '{}'", dir.display()))?;
+    let mut paths: Vec<PathBuf> = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let name = match input.file_name() {
+            Some(name) if input.as_os_str() != "-" => name.to_string_lossy(),
+            _ => "stdin".into(),
+        };
+        let path = dir.join(format!("{name}.{extension}"));
+        if paths.contains(&path) {
+            bail!(
+                "several inputs are named '{name}', their results would all go to '{}'",
+                path.display()
+            );
         }
+        paths.push(path);
     }
-    writer.write_all(b"}")?;
+    Ok(paths)
+}
 
+fn write_results(results: &Results, mut writer: impl Write, output: &dyn Output) -> Result<()> {
+    output.write(results, &mut writer)?;
+    writer.flush().context("unable to flush the results")?;
     Ok(())
 }
 
-fn merge_results(chunk_results: Vec<HashMap>) -> Vec<(Symbol, Sensor)> {
-    let mut all_sensors = HashMap::default();
-    for sensors in chunk_results {
-        for (name, s) in sensors {
-            all_sensors
-                .entry(name)
-                .and_modify(|sensor| sensor.merge(&s))
-                .or_insert(s);
+fn write_rejects(input: &Path, results: &Results, writer: &mut impl Write) -> std::io::Result<()> {
+    for reject in results.rejects().records() {
+        write!(
+            writer,
+            "{}:{}\t{}\t",
+            input.display(),
+            reject.offset,
+            reject.kind.name()
+        )?;
+        write_escaped(writer, &reject.record)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Writes `record` on a single line, the quoted fields being able to hold LF chars
+fn write_escaped(writer: &mut impl Write, record: &[u8]) -> std::io::Result<()> {
+    let mut start = 0;
+    for (index, &b) in record.iter().enumerate() {
+        let escape: &[u8] = match b {
+            b'\\' => b"\\\\",
+            b'\t' => b"\\t",
+            b'\r' => b"\\r",
+            b'\n' => b"\\n",
+            _ => continue,
+        };
+        writer.write_all(&record[start..index])?;
+        writer.write_all(escape)?;
+        start = index + 1;
+    }
+    writer.write_all(&record[start..])
+}
+
+/// Parses a single ASCII char, `\t` or `tab` into a byte
+fn parse_byte(value: &str) -> Result<u8, String> {
+    match value {
+        "\\t" | "tab" => Ok(b'\t'),
+        _ => match value.as_bytes() {
+            [byte] if byte.is_ascii() => Ok(*byte),
+            _ => Err(format!("expected a single ASCII char, got '{value}'")),
+        },
+    }
+}
+
+/// Parses the byte of --delimiter or --comment, which cannot be one of the temperatures, quotes or
+/// line endings, see [`Format::is_reserved`]
+fn parse_syntax_byte(value: &str) -> Result<u8, String> {
+    match parse_byte(value)? {
+        byte if Format::is_reserved(byte) => Err(format!(
+            "'{value}' is reserved, it cannot be a digit, '-', '.', '\"', CR or LF"
+        )),
+        byte => Ok(byte),
+    }
+}
+
+/// Renders a parse error the way compilers do, with the lines around the offending record when
+/// the whole input `buf` is known
+fn diagnostic(path: &Path, buf: Option<&[u8]>, err: &ParseError) -> String {
+    let lossy = |line: &[u8]| {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        String::from_utf8_lossy(line).into_owned()
+    };
+    let previous = buf
+        .and_then(|buf| buf[..err.offset].strip_suffix(b"\n"))
+        .map(|before| match before.iter().rposition(|&b| b == b'\n') {
+            Some(lf) => lossy(&before[lf + 1..]),
+            None => lossy(before),
+        });
+    let next = buf
+        .and_then(|buf| {
+            buf[err.offset..]
+                .split(|&b| b == b'\n')
+                .nth(lines(&err.record) + 1)
+        })
+        .filter(|line| !line.is_empty())
+        .map(lossy);
+
+    // the span is in bytes of the raw record, the columns in chars of its lossy conversion
+    let prefix = err.record.get(..err.span.start).unwrap_or_default();
+    let caret_line = err.line + lines(prefix);
+    let column = prefix
+        .rsplit(|&b| b == b'\n')
+        .next()
+        .map_or(0, |line| String::from_utf8_lossy(line).chars().count());
+    let span = err.record.get(err.span.clone()).unwrap_or_default();
+    let width = String::from_utf8_lossy(span).chars().count().max(1);
+    let last_line = err.line + lines(&err.record) + 1;
+    let gutter = last_line.to_string().len();
+
+    let mut out = format!("error: {}\n", err.kind);
+    out += &format!(
+        "{:gutter$}--> {}:{caret_line}:{} (byte {})\n",
+        "",
+        path.display(),
+        column + 1,
+        err.offset + err.span.start,
+    );
+    out += &format!("{:gutter$} |\n", "");
+    if let Some(previous) = previous {
+        out += &format!("{:>gutter$} | {previous}\n", err.line - 1);
+    }
+    for (index, line) in err.record.split(|&b| b == b'\n').enumerate() {
+        let line = String::from_utf8_lossy(line);
+        out += &format!("{:>gutter$} | {line}\n", err.line + index);
+        if err.line + index == caret_line {
+            out += &format!(
+                "{:gutter$} | {:column$}{} station {:?}\n",
+                "",
+                "",
+                "^".repeat(width),
+                err.station
+            );
         }
     }
-    let mut sensors: Vec<_> = all_sensors.into_iter().collect();
-    sensors.sort_by(|(a, _), (b, _)| a.cmp(b));
-    sensors
+    if let Some(next) = next {
+        out += &format!("{last_line:>gutter$} | {next}\n");
+    }
+    out
+}
+
+/// The number of LF in `bytes`
+fn lines(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&b| b == b'\n').count()
 }
 
-/// A chunk contains lines without overlapping
+/// Number of decimals of the floats, `None` for the shortest representation
 #[derive(Clone, Copy, Debug)]
-pub struct Chunk<'a> {
-    pub data: &'a [u8],
-    pub start: usize,
-    pub end: usize,
-}
-
-struct Sensor {
-    min: f32,
-    sum: f32,
-    cnt: usize,
-    max: f32,
-}
-
-impl Sensor {
-    fn new(temp: f32) -> Self {
-        Self {
-            cnt: 1,
-            min: temp,
-            max: temp,
-            sum: temp,
+struct Precision(Option<usize>);
+
+fn parse_accuracy(value: &str) -> Result<f64, String> {
+    match value.parse() {
+        Ok(accuracy) if (0.0001..=0.5).contains(&accuracy) => Ok(accuracy),
+        _ => Err(format!(
+            "expected a relative accuracy between 0.0001 and 0.5, got '{value}'"
+        )),
+    }
+}
+
+fn parse_percentile(value: &str) -> Result<f64, String> {
+    match value.parse() {
+        Ok(percentile) if (0.0..=100.0).contains(&percentile) => Ok(percentile),
+        _ => Err(format!(
+            "expected a percentile between 0 and 100, got '{value}'"
+        )),
+    }
+}
+
+fn parse_precision(value: &str) -> Result<Precision, String> {
+    match value {
+        "shortest" => Ok(Precision(None)),
+        _ => value
+            .parse()
+            .map(|decimals| Precision(Some(decimals)))
+            .map_err(|_| format!("expected a number of decimals or 'shortest', got '{value}'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnostic_of_non_utf8_records() {
+        let data = b"Abha;1.0\n\xff\xff;1.0\nAbha;2.0\n";
+        let err = Aggregator::new().run(data).unwrap_err();
+        let err = err.downcast::<ParseError>().unwrap();
+        let text = diagnostic(Path::new("a.txt"), Some(data), &err);
+        assert_eq!(
+            text,
+            "error: station name is not valid UTF-8\n \
+             --> a.txt:2:1 (byte 9)\n  \
+             |\n\
+             1 | Abha;1.0\n\
+             2 | \u{FFFD}\u{FFFD};1.0\n  \
+             | ^^ station \"\u{FFFD}\u{FFFD}\"\n\
+             3 | Abha;2.0\n"
+        );
+
+        let data = b"\xff;x\n";
+        let lossy = Aggregator::new().format(Format::new().utf8(Utf8::Lossy));
+        let err = lossy
+            .run(data)
+            .unwrap_err()
+            .downcast::<ParseError>()
+            .unwrap();
+        let text = diagnostic(Path::new("a.txt"), Some(data), &err);
+        assert!(text.contains("--> a.txt:1:3 (byte 2)"), "{text}");
+        assert!(text.contains("1 | \u{FFFD};x\n  |   ^ station"), "{text}");
+    }
+
+    #[test]
+    fn rejects_on_single_lines() {
+        let data = b"\"Foo\nBar\";x\nAbha;1.0\na\\b\t\r;y\n\"\n\";1.0\n";
+        let quarantine = Aggregator::new().on_error(OnError::Quarantine);
+        for chunks in [1, 3, data.len()] {
+            let results = quarantine.clone().chunks(chunks).run(data).unwrap();
+            let mut out = Vec::new();
+            write_rejects(Path::new("a.txt"), &results, &mut out).unwrap();
+            assert_eq!(
+                String::from_utf8(out).unwrap(),
+                "a.txt:0\tinvalid-temperature\t\"Foo\\nBar\";x\n\
+                 a.txt:21\tinvalid-temperature\ta\\\\b\\t\\r;y\n"
+            );
         }
     }
 
-    fn add_temp(&mut self, temp: f32) {
-        if temp < self.min {
-            self.min = temp;
+    #[test]
+    fn wildcards() {
+        let matches = |pattern: &str, name: &str| {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            matches(&pattern, &name)
+        };
+        let cases = [
+            ("*.csv", "a.csv", true),
+            ("*.csv", ".csv", true),
+            ("*.csv", "a.csv.gz", false),
+            ("a*b*c", "abbbc", true),
+            ("a*b*c", "acb", false),
+            ("*", "", true),
+            ("?.csv", "a.csv", true),
+            ("?.csv", "ab.csv", false),
+            ("?", "", false),
+            ("[a-c].csv", "b.csv", true),
+            ("[a-c].csv", "d.csv", false),
+            ("[a-cx].csv", "x.csv", true),
+            ("[ab-].csv", "-.csv", true),
+            ("[!x].csv", "a.csv", true),
+            ("[!x].csv", "x.csv", false),
+            ("[^x].csv", "x.csv", false),
+            ("[!x].csv", ".csv", false),
+            // a leading `]` is part of the class
+            ("[]a].csv", "].csv", true),
+            ("[]a].csv", "a.csv", true),
+            ("[!]].csv", "].csv", false),
+            ("[!]].csv", "a.csv", true),
+            // an unclosed `[` is a plain char
+            ("a[b", "a[b", true),
+            ("a[b", "ab", false),
+            ("[", "[", true),
+            ("[]", "[]", true),
+            ("[*", "[ab", true),
+        ];
+        for (pattern, name, expected) in cases {
+            assert_eq!(matches(pattern, name), expected, "{pattern} {name}");
         }
-        if temp > self.max {
-            self.max = temp;
+    }
+
+    #[test]
+    fn expanded_patterns() {
+        let dir = std::env::temp_dir().join(format!("chunkit-expand-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("c.csv")).unwrap();
+        for name in ["a.csv", "b.csv", ".hidden.csv", "d.txt"] {
+            std::fs::write(dir.join(name), "").unwrap();
         }
-        self.sum += temp;
-        self.cnt += 1;
+        let expand = |pattern: &str| expand(&dir.join(pattern)).unwrap();
+
+        // hidden files only match an explicit dot, and directories never match
+        assert_eq!(expand("*.csv"), [dir.join("a.csv"), dir.join("b.csv")]);
+        assert_eq!(expand(".*.csv"), [dir.join(".hidden.csv")]);
+        assert_eq!(
+            expand("?.*"),
+            [dir.join("a.csv"), dir.join("b.csv"), dir.join("d.txt")]
+        );
+        assert_eq!(expand("[!a].csv"), [dir.join("b.csv")]);
+        assert_eq!(expand("*.json"), Vec::<PathBuf>::new());
+        // without wildcards, a path is kept even when it does not exist
+        assert_eq!(expand("e.csv"), [dir.join("e.csv")]);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
-    fn merge(&mut self, sensor: &Sensor) {
-        if self.min > sensor.min {
-            self.min = sensor.min;
+    #[test]
+    fn quiet_before_the_subcommands() {
+        let parse = |args: &str| parse_cli(args.split(' ').map(OsString::from));
+        for args in ["chunkit -q partial a -o x", "chunkit partial -q a -o x"] {
+            let cli = parse(args).unwrap();
+            assert!(cli.quiet, "{args}");
+            assert!(matches!(
+                cli.command,
+                Some(Command::Partial {
+                    output: Some(_),
+                    ..
+                })
+            ));
         }
-        if self.max < sensor.max {
-            self.max = sensor.max;
+        let cli = parse("chunkit --quiet combine a.chkp").unwrap();
+        assert!(cli.quiet && matches!(cli.command, Some(Command::Combine { .. })));
+
+        // the other top-level arguments would be silently ignored
+        for args in ["chunkit --header partial a", "chunkit -o x combine a.chkp"] {
+            let err = parse(args).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ArgumentConflict, "{args}");
         }
-        self.sum += sensor.sum;
-        self.cnt += sensor.cnt;
     }
-}
 
-impl Default for Sensor {
-    fn default() -> Self {
-        Self {
-            min: f32::MAX,
-            sum: 0.0,
-            cnt: 0,
-            max: f32::MIN,
+    #[test]
+    fn reserved_syntax_bytes() {
+        let parse = |args: &[&str]| parse_cli(args.iter().map(OsString::from));
+        for args in [
+            ["chunkit", "-d", "\"", "a"],
+            ["chunkit", "-d", "1", "a"],
+            ["chunkit", "-d", "-", "a"],
+            ["chunkit", "-d", ".", "a"],
+            ["chunkit", "-d", "\n", "a"],
+            ["chunkit", "-d", "é", "a"],
+            ["chunkit", "--comment", "\r", "a"],
+            ["chunkit", "--comment", "0", "a"],
+        ] {
+            let err = parse(&args).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ValueValidation, "{args:?}");
+        }
+        for args in [
+            &["chunkit", "--comment", ";", "a"][..],
+            &["chunkit", "-d", "#", "--comment", "#", "a"],
+            &["chunkit", "partial", "--comment", ";", "a"],
+        ] {
+            let err = parse(args).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ArgumentConflict, "{args:?}");
         }
-    }
-}
 
-impl serde::Serialize for Sensor {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        use serde::ser::SerializeStruct;
-        let mut s = serializer.serialize_struct("Sensor", 4)?;
-        s.serialize_field("min", &self.min)?;
-        s.serialize_field("avg", &(self.sum / self.cnt as f32))?;
-        s.serialize_field("max", &self.max)?;
-        s.serialize_field("count", &self.cnt)?;
-        s.end()
+        let cli = parse(&["chunkit", "-d", "tab", "--comment", "#", "a"]).unwrap();
+        assert_eq!(
+            (cli.aggregate.delimiter, cli.aggregate.comment),
+            (b'\t', Some(b'#'))
+        );
+        // the decimal separator of the outputs is not part of the syntax of the inputs
+        let cli = parse(&["chunkit", "--decimal", ",", "a"]).unwrap();
+        assert_eq!(cli.output.decimal, b',');
     }
 }
@@ -0,0 +1,60 @@
+/// The central moments of the readings of a station, up to the fourth.
+///
+/// They are updated one reading at a time with Welford's algorithm, and merged with the pairwise
+/// formulas of Chan et al., both generalized to the third and fourth moments by Pébay. This stays
+/// accurate where the naive sums of powers cancel out catastrophically, and gives the same results
+/// whatever the way the readings are split among the workers, up to rounding.
+///
+/// The readings are in tenths of degree, so that they are exact integers, and the number of
+/// readings is the count of the [`Sensor`](crate::Sensor) holding the moments.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Moments {
+    pub(crate) mean: f64,
+    /// The sums of the powers of the deviations from the mean
+    pub(crate) m2: f64,
+    pub(crate) m3: f64,
+    pub(crate) m4: f64,
+}
+
+impl Moments {
+    /// Adds the reading `temp` to the `count` ones seen so far
+    #[inline]
+    pub(crate) fn add(&mut self, count: usize, temp: i16) {
+        let n = (count + 1) as f64;
+        let delta = temp as f64 - self.mean;
+        let delta_n = delta / n;
+        let delta_n2 = delta_n * delta_n;
+        let term = delta * delta_n * count as f64;
+
+        self.mean += delta_n;
+        self.m4 += term * delta_n2 * (n * n - 3.0 * n + 3.0) + 6.0 * delta_n2 * self.m2
+            - 4.0 * delta_n * self.m3;
+        self.m3 += term * delta_n * (n - 2.0) - 3.0 * delta_n * self.m2;
+        self.m2 += term;
+    }
+
+    /// The moments of the `count + other_count` readings of `self` and `other`
+    pub(crate) fn merge(&self, count: usize, other: &Moments, other_count: usize) -> Moments {
+        let (na, nb) = (count as f64, other_count as f64);
+        let n = na + nb;
+        if n == 0.0 {
+            return Moments::default();
+        }
+        let delta = other.mean - self.mean;
+        let (delta2, delta3) = (delta * delta, delta * delta * delta);
+
+        Moments {
+            mean: self.mean + delta * nb / n,
+            m2: self.m2 + other.m2 + delta2 * na * nb / n,
+            m3: self.m3
+                + other.m3
+                + delta3 * na * nb * (na - nb) / (n * n)
+                + 3.0 * delta * (na * other.m2 - nb * self.m2) / n,
+            m4: self.m4
+                + other.m4
+                + delta2 * delta2 * na * nb * (na * na - na * nb + nb * nb) / (n * n * n)
+                + 6.0 * delta2 * (na * na * other.m2 + nb * nb * self.m2) / (n * n)
+                + 4.0 * delta * (na * other.m3 - nb * self.m3) / n,
+        }
+    }
+}
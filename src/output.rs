@@ -0,0 +1,568 @@
+//! Writers of the [`Results`], behind the common [`Output`] trait.
+//!
+//! Inputs without any record, eg. empty or header-only ones, aggregate to empty results which
+//! every writer handles:
+//!
+//! ```
+//! use chunkit::output::{Csv, Output};
+//! use chunkit::{Aggregator, Format};
+//!
+//! let header_only = Aggregator::new().format(Format::new().header(true));
+//! let results = header_only.run(b"station;temperature\n")?;
+//! let mut out = Vec::new();
+//! Csv::new().write(&results, &mut out)?;
+//! assert_eq!(out, b"station,min,mean,max,count\n");
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The writers only write the stations. The records skipped with
+//! [`OnError::Skip`](crate::OnError) are counted in [`Results::rejects`] instead, which the
+//! command line tool prints on stderr, so that every key of the JSON object, line of the NDJSON
+//! and row of the CSV is a station.
+
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use serde::ser::{Error as _, SerializeMap};
+use serde::{Serialize, Serializer};
+use serde_json::value::RawValue;
+
+use crate::{Results, Rounding, Sensor};
+
+/// A layout the results can be written in
+pub trait Output {
+    fn write(&self, results: &Results, writer: &mut dyn Write) -> Result<()>;
+}
+
+/// The 1BRC layout: `{name=min/mean/max, ...}`
+///
+/// The values are written with one decimal, rounded from their exact value with `rounding`.
+///
+/// ```
+/// use chunkit::output::{Output, Text};
+/// use chunkit::{Aggregator, Rounding};
+///
+/// let data = b"a;-0.1\na;0.0\nb;0.1\nb;0.0\nc;1.2\nc;1.3\nc;1.2\nc;1.3\n";
+/// let results = Aggregator::new().threads(1).run(data)?;
+/// let text = |rounding| -> anyhow::Result<String> {
+///     let mut out = Vec::new();
+///     Text { rounding }.write(&results, &mut out)?;
+///     Ok(String::from_utf8(out)?)
+/// };
+/// assert_eq!(text(Rounding::CeilHalf)?, "{a=-0.1/0.0/0.0, b=0.0/0.1/0.1, c=1.2/1.3/1.3}");
+/// assert_eq!(text(Rounding::HalfEven)?, "{a=-0.1/0.0/0.0, b=0.0/0.0/0.1, c=1.2/1.2/1.3}");
+/// assert_eq!(
+///     text(Rounding::HalfAwayFromZero)?,
+///     "{a=-0.1/-0.1/0.0, b=0.0/0.1/0.1, c=1.2/1.3/1.3}"
+/// );
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Text {
+    pub rounding: Rounding,
+}
+
+impl Output for Text {
+    fn write(&self, results: &Results, writer: &mut dyn Write) -> Result<()> {
+        writer.write_all(b"{")?;
+        for (index, (name, sensor)) in results.iter().enumerate() {
+            if index > 0 {
+                writer.write_all(b", ")?;
+            }
+            let [min, mean, max] = sensor.format(self.rounding, 1);
+            writer
+                .write_fmt(format_args!("{name}={min}/{mean}/{max}"))
+                .context("unable to write")?;
+        }
+        writer.write_all(b"}")?;
+
+        Ok(())
+    }
+}
+
+/// A single JSON object keyed by station, in name order:
+/// `{"Abha":{"min":-32.6,"avg":18.0,"max":70.1,"count":12345},...}`
+///
+/// Floats are written with `precision` decimals, or as short as possible when `None`. With a
+/// `precision`, the min, mean and max are rounded from their exact value with `rounding`, as with
+/// the other layouts. Each of the `percentiles` adds a `p<percentile>` field, eg. `"p99":65.2`,
+/// null unless the histograms or the sketches are kept (see [`Sensor::percentile`]).
+#[derive(Clone, Debug)]
+pub struct Json {
+    pub precision: Option<usize>,
+    pub rounding: Rounding,
+    pub percentiles: Vec<f64>,
+}
+
+impl Output for Json {
+    fn write(&self, results: &Results, writer: &mut dyn Write) -> Result<()> {
+        let serializer =
+            &mut serde_json::Serializer::with_formatter(writer, Precision(self.precision));
+        let rows = results.iter().map(|(station, sensor)| {
+            let row = Row::new(sensor, self.precision, self.rounding, &self.percentiles);
+            (station, row)
+        });
+        serializer.collect_map(rows).context("unable to write")?;
+        Ok(())
+    }
+}
+
+/// Newline delimited JSON, one object per station in name order:
+/// `{"station":"Abha","min":-32.6,"avg":18.0,"max":70.1,"count":12345}`
+///
+/// The floats and the `percentiles` are written as with [`Json`].
+#[derive(Clone, Debug)]
+pub struct Ndjson {
+    pub precision: Option<usize>,
+    pub rounding: Rounding,
+    pub percentiles: Vec<f64>,
+}
+
+impl Output for Ndjson {
+    fn write(&self, results: &Results, writer: &mut dyn Write) -> Result<()> {
+        #[derive(Serialize)]
+        struct Line<'a> {
+            station: &'a str,
+            #[serde(flatten)]
+            row: Row<'a>,
+        }
+
+        for (station, sensor) in results.iter() {
+            let mut serializer =
+                serde_json::Serializer::with_formatter(&mut *writer, Precision(self.precision));
+            let row = Row::new(sensor, self.precision, self.rounding, &self.percentiles);
+            Line { station, row }
+                .serialize(&mut serializer)
+                .context("unable to write")?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// The fields of a sensor followed by the requested percentiles
+struct Row<'a> {
+    sensor: &'a Sensor,
+    precision: Option<usize>,
+    rounding: Rounding,
+    percentiles: &'a [f64],
+}
+
+impl<'a> Row<'a> {
+    fn new(
+        sensor: &'a Sensor,
+        precision: Option<usize>,
+        rounding: Rounding,
+        percentiles: &'a [f64],
+    ) -> Self {
+        Self {
+            sensor,
+            precision,
+            rounding,
+            percentiles,
+        }
+    }
+}
+
+impl Serialize for Row<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let sensor = self.sensor;
+        let mut map = serializer.serialize_map(None)?;
+        match self.precision {
+            // written as is, rather than through a float which would round them again
+            Some(precision) => {
+                let [min, mean, max] = sensor.format(self.rounding, precision);
+                for (key, value) in [("min", min), ("avg", mean), ("max", max)] {
+                    let value = RawValue::from_string(value).map_err(S::Error::custom)?;
+                    map.serialize_entry(key, &value)?;
+                }
+            }
+            None => {
+                map.serialize_entry("min", &sensor.min())?;
+                map.serialize_entry("avg", &sensor.mean())?;
+                map.serialize_entry("max", &sensor.max())?;
+            }
+        }
+        map.serialize_entry("count", &sensor.count())?;
+        if sensor.moments().is_some() {
+            map.serialize_entry("variance", &sensor.variance())?;
+            map.serialize_entry("stddev", &sensor.std_dev())?;
+            map.serialize_entry("skewness", &sensor.skewness())?;
+            map.serialize_entry("kurtosis", &sensor.kurtosis())?;
+        }
+        for &p in self.percentiles {
+            map.serialize_entry(&format!("p{p}"), &sensor.percentile(p))?;
+        }
+        map.end()
+    }
+}
+
+/// JSON formatter writing floats with a fixed number of decimals
+struct Precision(Option<usize>);
+
+impl serde_json::ser::Formatter for Precision {
+    fn write_f64<W>(&mut self, writer: &mut W, value: f64) -> std::io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        match self.0 {
+            Some(precision) if value.is_finite() => write!(writer, "{value:.precision$}"),
+            _ => serde_json::ser::CompactFormatter.write_f64(writer, value),
+        }
+    }
+}
+
+/// Delimiter separated values with a `station,min,mean,max,count` header, in name order, followed
+/// by `variance,stddev,skewness,kurtosis` when the moments are tracked and by a `p<percentile>`
+/// column for each of the `percentiles`.
+///
+/// With a `precision`, the values are rounded from their exact value with `rounding`. Records end
+/// with LF. Fields containing the delimiter, a quote, CR or LF are quoted as per
+/// RFC 4180, which includes the numbers when the decimal separator is the delimiter.
+///
+/// ```
+/// use chunkit::output::Csv;
+///
+/// let spreadsheet = Csv::new().delimiter(b';').decimal(b',');
+/// let tsv = Csv::tsv();
+/// let median = Csv::new().percentiles(vec![50.0]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Csv {
+    delimiter: u8,
+    decimal: u8,
+    precision: Option<usize>,
+    rounding: Rounding,
+    percentiles: Vec<f64>,
+}
+
+impl Csv {
+    /// Comma separated, with `.` as decimal separator and one decimal
+    pub fn new() -> Self {
+        Self {
+            delimiter: b',',
+            decimal: b'.',
+            precision: Some(1),
+            rounding: Rounding::CeilHalf,
+            percentiles: Vec::new(),
+        }
+    }
+
+    /// Tab separated, with `.` as decimal separator and one decimal
+    pub fn tsv() -> Self {
+        Self::new().delimiter(b'\t')
+    }
+
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets the decimal separator, eg. `,` for most European locales
+    pub fn decimal(mut self, decimal: u8) -> Self {
+        self.decimal = decimal;
+        self
+    }
+
+    /// Sets the number of decimals, `None` writes the floats as short as possible
+    pub fn precision(mut self, precision: Option<usize>) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    pub fn rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Adds a column for each of the percentiles (in [0, 100]), empty unless the histograms or the
+    /// sketches are kept, see [`Sensor::percentile`]
+    pub fn percentiles(mut self, percentiles: Vec<f64>) -> Self {
+        self.percentiles = percentiles;
+        self
+    }
+
+    fn write_field(&self, writer: &mut dyn Write, field: &[u8]) -> std::io::Result<()> {
+        let quote = field
+            .iter()
+            .any(|&b| b == self.delimiter || matches!(b, b'"' | b'\r' | b'\n'));
+        if !quote {
+            return writer.write_all(field);
+        }
+        writer.write_all(b"\"")?;
+        for (index, part) in field.split(|&b| b == b'"').enumerate() {
+            if index > 0 {
+                writer.write_all(b"\"\"")?;
+            }
+            writer.write_all(part)?;
+        }
+        writer.write_all(b"\"")
+    }
+
+    fn write_float(&self, writer: &mut dyn Write, value: String) -> std::io::Result<()> {
+        let mut text = value.into_bytes();
+        for b in text.iter_mut().filter(|b| **b == b'.') {
+            *b = self.decimal;
+        }
+        self.write_field(writer, &text)
+    }
+}
+
+impl Default for Csv {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Output for Csv {
+    fn write(&self, results: &Results, writer: &mut dyn Write) -> Result<()> {
+        let sep = [self.delimiter];
+        let moments = results.has_moments();
+        let mut columns: Vec<String> = ["station", "min", "mean", "max", "count"]
+            .map(String::from)
+            .into();
+        if moments {
+            columns.extend(["variance", "stddev", "skewness", "kurtosis"].map(String::from));
+        }
+        columns.extend(self.percentiles.iter().map(|p| format!("p{p}")));
+        for (index, column) in columns.iter().enumerate() {
+            if index > 0 {
+                writer.write_all(&sep)?;
+            }
+            writer.write_all(column.as_bytes())?;
+        }
+        writer.write_all(b"\n")?;
+
+        for (name, sensor) in results.iter() {
+            self.write_field(writer, name.as_bytes())?;
+            let values = match self.precision {
+                Some(precision) => sensor.format(self.rounding, precision),
+                None => [sensor.min(), sensor.mean(), sensor.max()].map(|value| value.to_string()),
+            };
+            for value in values {
+                writer.write_all(&sep)?;
+                self.write_float(writer, value)?;
+            }
+            writer.write_all(&sep)?;
+            writer.write_fmt(format_args!("{}", sensor.count()))?;
+            let mut values = Vec::new();
+            if moments {
+                values.extend([
+                    sensor.variance(),
+                    sensor.std_dev(),
+                    sensor.skewness(),
+                    sensor.kurtosis(),
+                ]);
+            }
+            values.extend(self.percentiles.iter().map(|&p| sensor.percentile(p)));
+            for value in values {
+                writer.write_all(&sep)?;
+                // undefined when all the readings are equal, or when not tracked
+                if let Some(value) = value {
+                    self.write_float(
+                        writer,
+                        match self.precision {
+                            Some(precision) => format!("{value:.precision$}"),
+                            None => value.to_string(),
+                        },
+                    )?;
+                }
+            }
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Aggregator, Format};
+
+    /// The means of `a` and `b` are -0.05 and -0.025, of `c` 0.05
+    const DATA: &[u8] = b"a;-0.1\na;0.0\nb;-0.1\nb;0.0\nb;0.0\nb;0.0\nc;0.1\nc;0.0\n";
+
+    fn write(output: &dyn Output) -> String {
+        let results = Aggregator::new().run(DATA).unwrap();
+        let mut out = Vec::new();
+        output.write(&results, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn json(precision: Option<usize>, rounding: Rounding) -> String {
+        write(&Json {
+            precision,
+            rounding,
+            percentiles: vec![],
+        })
+    }
+
+    #[test]
+    fn empty_results() {
+        let header = Format::new().header(true);
+        let inputs: [(&[u8], Format); 6] = [
+            (b"", Format::new()),
+            (b"\xEF\xBB\xBF", Format::new()),
+            (b"station;temperature\n", header),
+            (b"station;temperature", header),
+            (b"# no readings\n", Format::new().comment(Some(b'#'))),
+            (b"\xEF\xBB\xBFstation;temperature\r\n", header),
+        ];
+        let rounding = Rounding::CeilHalf;
+        let outputs: [(&dyn Output, &str); 5] = [
+            (&Text::default(), "{}"),
+            (
+                &Json {
+                    precision: Some(1),
+                    rounding,
+                    percentiles: vec![50.0],
+                },
+                "{}",
+            ),
+            (
+                &Ndjson {
+                    precision: Some(1),
+                    rounding,
+                    percentiles: vec![],
+                },
+                "",
+            ),
+            (&Csv::new(), "station,min,mean,max,count\n"),
+            (&Csv::tsv(), "station\tmin\tmean\tmax\tcount\n"),
+        ];
+        for (input, format) in inputs {
+            for chunks in 1..=input.len() + 1 {
+                let aggregator = Aggregator::new().chunks(chunks).format(format);
+                let results = aggregator.run(input).unwrap();
+                assert!(results.is_empty());
+                for (output, expected) in outputs {
+                    let mut out = Vec::new();
+                    output.write(&results, &mut out).unwrap();
+                    assert_eq!(String::from_utf8(out).unwrap(), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn single_record() {
+        // with or without its trailing LF, it is not spread over more chunks than it has bytes
+        for input in [&b"Abha;-1.2\n"[..], b"Abha;-1.2"] {
+            for chunks in 1..=input.len() + 1 {
+                let results = Aggregator::new().chunks(chunks).run(input).unwrap();
+                let mut out = Vec::new();
+                Text::default().write(&results, &mut out).unwrap();
+                assert_eq!(String::from_utf8(out).unwrap(), "{Abha=-1.2/-1.2/-1.2}");
+            }
+        }
+    }
+
+    #[test]
+    fn rounded_means() {
+        let round = |rounding| {
+            let text = write(&Text { rounding });
+            let csv = write(&Csv::new().rounding(rounding));
+            let json = json(Some(1), rounding);
+            let ndjson = write(&Ndjson {
+                precision: Some(1),
+                rounding,
+                percentiles: vec![],
+            });
+            [text, csv, json, ndjson]
+        };
+        assert_eq!(
+            round(Rounding::CeilHalf),
+            [
+                "{a=-0.1/0.0/0.0, b=-0.1/0.0/0.0, c=0.0/0.1/0.1}",
+                "station,min,mean,max,count\na,-0.1,0.0,0.0,2\nb,-0.1,0.0,0.0,4\nc,0.0,0.1,0.1,2\n",
+                concat!(
+                    r#"{"a":{"min":-0.1,"avg":0.0,"max":0.0,"count":2},"#,
+                    r#""b":{"min":-0.1,"avg":0.0,"max":0.0,"count":4},"#,
+                    r#""c":{"min":0.0,"avg":0.1,"max":0.1,"count":2}}"#
+                ),
+                concat!(
+                    r#"{"station":"a","min":-0.1,"avg":0.0,"max":0.0,"count":2}"#,
+                    "\n",
+                    r#"{"station":"b","min":-0.1,"avg":0.0,"max":0.0,"count":4}"#,
+                    "\n",
+                    r#"{"station":"c","min":0.0,"avg":0.1,"max":0.1,"count":2}"#,
+                    "\n"
+                ),
+            ]
+        );
+        assert_eq!(
+            round(Rounding::HalfAwayFromZero),
+            [
+                "{a=-0.1/-0.1/0.0, b=-0.1/0.0/0.0, c=0.0/0.1/0.1}",
+                "station,min,mean,max,count\na,-0.1,-0.1,0.0,2\nb,-0.1,0.0,0.0,4\nc,0.0,0.1,0.1,2\n",
+                concat!(
+                    r#"{"a":{"min":-0.1,"avg":-0.1,"max":0.0,"count":2},"#,
+                    r#""b":{"min":-0.1,"avg":0.0,"max":0.0,"count":4},"#,
+                    r#""c":{"min":0.0,"avg":0.1,"max":0.1,"count":2}}"#
+                ),
+                concat!(
+                    r#"{"station":"a","min":-0.1,"avg":-0.1,"max":0.0,"count":2}"#,
+                    "\n",
+                    r#"{"station":"b","min":-0.1,"avg":0.0,"max":0.0,"count":4}"#,
+                    "\n",
+                    r#"{"station":"c","min":0.0,"avg":0.1,"max":0.1,"count":2}"#,
+                    "\n"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_precisions() {
+        assert_eq!(
+            json(Some(0), Rounding::HalfEven),
+            concat!(
+                r#"{"a":{"min":0,"avg":0,"max":0,"count":2},"#,
+                r#""b":{"min":0,"avg":0,"max":0,"count":4},"#,
+                r#""c":{"min":0,"avg":0,"max":0,"count":2}}"#
+            )
+        );
+        assert_eq!(
+            json(Some(3), Rounding::CeilHalf),
+            concat!(
+                r#"{"a":{"min":-0.100,"avg":-0.050,"max":0.000,"count":2},"#,
+                r#""b":{"min":-0.100,"avg":-0.025,"max":0.000,"count":4},"#,
+                r#""c":{"min":0.000,"avg":0.050,"max":0.100,"count":2}}"#
+            )
+        );
+        assert_eq!(
+            json(None, Rounding::CeilHalf),
+            concat!(
+                r#"{"a":{"min":-0.1,"avg":-0.05,"max":0.0,"count":2},"#,
+                r#""b":{"min":-0.1,"avg":-0.025,"max":0.0,"count":4},"#,
+                r#""c":{"min":0.0,"avg":0.05,"max":0.1,"count":2}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn json_with_moments_and_percentiles() {
+        let results = Aggregator::new()
+            .moments(true)
+            .histograms(true)
+            .run(b"a;-0.1\na;0.0\n")
+            .unwrap();
+        let mut out = Vec::new();
+        let json = Json {
+            precision: Some(2),
+            rounding: Rounding::CeilHalf,
+            percentiles: vec![50.0, 100.0],
+        };
+        json.write(&results, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"{"a":{"min":-0.10,"avg":-0.05,"max":0.00,"count":2,"variance":0.00,"#,
+                r#""stddev":0.05,"skewness":0.00,"kurtosis":-2.00,"p50":-0.05,"p100":0.00}}"#
+            )
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//! A compact binary format for partial aggregates, so that shards aggregated on different
+//! machines can be merged exactly.
+//!
+//! The exact state of every sensor is kept (min, sum, count and max in tenths of degree), never
+//! a rounded mean, so that merging partials gives the same results as aggregating all the inputs
+//! at once:
+//!
+//! ```
+//! use chunkit::output::Output;
+//! use chunkit::partial::{self, Partial};
+//! use chunkit::{Aggregator, OnError, Results};
+//!
+//! let input = b"Abha;1.0\nBaku;-0.1\nAbha;0.2\nBaku;x\n\"A;b\";3.3\nAbha;-0.4\n";
+//! let aggregator = Aggregator::new().on_error(OnError::Skip);
+//! let mut combined = Results::default();
+//! for shard in [&input[..19], &input[19..35], &input[35..]] {
+//!     let mut file = Vec::new();
+//!     Partial.write(&aggregator.run(shard)?, &mut file)?;
+//!     combined.merge(partial::read(&mut &file[..])?);
+//! }
+//! let all = aggregator.run(input)?;
+//! assert_eq!(format!("{:?}", combined), format!("{:?}", all));
+//! assert_eq!(combined.rejects().total(), 1);
+//!
+//! assert!(partial::read(&mut &b"Abha;1.0\n"[..]).is_err());
+//!
+//! // the moments are kept too, when tracked
+//! let moments = aggregator.clone().moments(true).run(input)?;
+//! let mut file = Vec::new();
+//! Partial.write(&moments, &mut file)?;
+//! let abha = partial::read(&mut &file[..])?.get("Abha").unwrap().clone();
+//! assert_eq!(abha.kurtosis(), moments.get("Abha").unwrap().kurtosis());
+//!
+//! // and so are the histograms
+//! let mut combined = Results::default();
+//! for shard in [&input[..19], &input[19..35], &input[35..]] {
+//!     let mut file = Vec::new();
+//!     Partial.write(&aggregator.clone().histograms(true).run(shard)?, &mut file)?;
+//!     combined.merge(partial::read(&mut &file[..])?);
+//! }
+//! assert_eq!(combined.get("Abha").unwrap().median(), Some(0.2));
+//!
+//! // as well as the sketches
+//! let sketches = aggregator.clone().sketches(Some(0.01)).run(input)?;
+//! let mut file = Vec::new();
+//! Partial.write(&sketches, &mut file)?;
+//! let abha = partial::read(&mut &file[..])?.get("Abha").unwrap().clone();
+//! assert_eq!(abha.median(), sketches.get("Abha").unwrap().median());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The layout of version 2, whose integers are LEB128 varints, zigzag encoded when signed:
+//!
+//! ```text
+//! magic       "CHKP"
+//! version     u16, little endian
+//! flags       varint, bit 0 when the moments are tracked, bit 1 when the histograms are kept,
+//!             bit 2 when the sketches are
+//! kinds       varint, then the number of records skipped for each `ParseErrorKind`
+//! stations    varint, then for each station:
+//!     name    varint length, then the UTF-8 bytes of the unquoted name
+//!     min     signed varint, in tenths of degree
+//!     max     signed varint, in tenths of degree
+//!     sum     signed varint, in tenths of degree
+//!     count   varint, at least 1
+//!     moments with flag 0, the mean, m2, m3 and m4 of the readings in tenths of degree, as
+//!             little endian f64
+//!     histogram
+//!             with flag 1, the varint number of distinct temperatures, then for each of them in
+//!             increasing order the varint difference with the previous one (with the min for the
+//!             first one) and the varint number of readings
+//!     sketch  with flag 2, the relative accuracy as a little endian f64, the varint number of
+//!             readings close to zero, then the buckets of the positive readings and those of
+//!             the negative ones, each as the varint number of buckets, the signed varint index of
+//!             the first one and the varint count of each of them
+//! ```
+//!
+//! Version 1 is the same without the flags. The quarantined records are not part of the
+//! partials, only their counts.
+
+use std::io::{Read, Write};
+
+use anyhow::{bail, Context, Result};
+
+use crate::aggregator::HashMap;
+use crate::histogram::Histogram;
+use crate::moments::Moments;
+use crate::output::Output;
+use crate::sketch::{Buckets, Sketch, MAX_BUCKETS};
+use crate::{ParseErrorKind, Rejects, Results, Sensor};
+
+const MAGIC: &[u8; 4] = b"CHKP";
+
+/// The version written, the previous ones are read too
+pub const VERSION: u16 = 2;
+
+/// The sensors come with their moments
+const MOMENTS: u64 = 1;
+/// The sensors come with their histogram
+const HISTOGRAMS: u64 = 2;
+/// The sensors come with their sketch
+const SKETCHES: u64 = 4;
+
+/// Writes the results as a partial aggregate, see [`read`] to read them back
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Partial;
+
+impl Output for Partial {
+    fn write(&self, results: &Results, writer: &mut dyn Write) -> Result<()> {
+        let mut out = Vec::with_capacity(16 + results.len() * 24);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        // as when merging sensors, the moments are lost unless every sensor tracks them
+        let moments = results.has_moments();
+        let histograms = results.has_histograms();
+        let sketches = results.has_sketches();
+        let mut flags = 0;
+        if moments {
+            flags |= MOMENTS;
+        }
+        if histograms {
+            flags |= HISTOGRAMS;
+        }
+        if sketches {
+            flags |= SKETCHES;
+        }
+        write_varint(&mut out, flags);
+
+        let rejects = results.rejects();
+        write_varint(&mut out, ParseErrorKind::ALL.len() as u64);
+        for kind in ParseErrorKind::ALL {
+            write_varint(&mut out, rejects.count(kind) as u64);
+        }
+
+        write_varint(&mut out, results.len() as u64);
+        for (name, sensor) in results.iter() {
+            write_varint(&mut out, name.len() as u64);
+            out.extend_from_slice(name.as_bytes());
+            write_varint(&mut out, zigzag(sensor.min_tenths() as i64));
+            write_varint(&mut out, zigzag(sensor.max_tenths() as i64));
+            write_varint(&mut out, zigzag(sensor.sum_tenths()));
+            write_varint(&mut out, sensor.count() as u64);
+            if moments {
+                let moments = sensor.moments().copied().unwrap_or_default();
+                for value in [moments.mean, moments.m2, moments.m3, moments.m4] {
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            if let Some(histogram) = sensor.histogram().filter(|_| histograms) {
+                write_varint(&mut out, histogram.iter().count() as u64);
+                let mut previous = sensor.min_tenths();
+                for (temp, count) in histogram.iter() {
+                    write_varint(&mut out, (temp - previous) as u64);
+                    write_varint(&mut out, count);
+                    previous = temp;
+                }
+            }
+            if let Some(sketch) = sensor.sketch().filter(|_| sketches) {
+                out.extend_from_slice(&sketch.accuracy().to_le_bytes());
+                write_varint(&mut out, sketch.zeros());
+                for buckets in sketch.buckets() {
+                    write_varint(&mut out, buckets.counts().len() as u64);
+                    write_varint(&mut out, zigzag(buckets.offset() as i64));
+                    for &count in buckets.counts() {
+                        write_varint(&mut out, count);
+                    }
+                }
+            }
+        }
+        writer
+            .write_all(&out)
+            .context("unable to write the partial aggregate")
+    }
+}
+
+/// Reads a partial aggregate written by [`Partial`]
+pub fn read(reader: &mut dyn Read) -> Result<Results> {
+    let mut buf = Vec::new();
+    reader
+        .read_to_end(&mut buf)
+        .context("unable to read the partial aggregate")?;
+    let mut data = &buf[..];
+
+    let Some(rest) = data.strip_prefix(MAGIC) else {
+        bail!("not a partial aggregate");
+    };
+    let Some((version, rest)) = rest.split_first_chunk() else {
+        bail!("truncated partial aggregate");
+    };
+    let version = u16::from_le_bytes(*version);
+    if !(1..=VERSION).contains(&version) {
+        bail!("unsupported partial aggregate version {version}, expected at most {VERSION}");
+    }
+    data = rest;
+    let flags = if version >= 2 {
+        read_varint(&mut data)?
+    } else {
+        0
+    };
+    if flags & !(MOMENTS | HISTOGRAMS | SKETCHES) != 0 {
+        bail!("unknown partial aggregate flags {flags:#x}");
+    }
+
+    let mut rejects = Rejects::default();
+    let nb_kinds = read_varint(&mut data)?;
+    if nb_kinds > ParseErrorKind::ALL.len() as u64 {
+        bail!(
+            "{nb_kinds} kinds of rejects, chunkit only knows about {}",
+            ParseErrorKind::ALL.len()
+        );
+    }
+    for kind in &ParseErrorKind::ALL[..nb_kinds as usize] {
+        rejects.add_count(*kind, read_varint(&mut data)? as usize);
+    }
+
+    let nb_stations = read_varint(&mut data)?;
+    let mut sensors = HashMap::default();
+    for _ in 0..nb_stations {
+        let len = read_varint(&mut data)? as usize;
+        let Some((name, rest)) = data.split_at_checked(len) else {
+            bail!("truncated partial aggregate");
+        };
+        let name = std::str::from_utf8(name).context("invalid station name")?;
+        data = rest;
+
+        let temp = |data: &mut &[u8]| {
+            let temp = unzigzag(read_varint(data)?);
+            i16::try_from(temp)
+                .with_context(|| format!("temperature {temp} of {name:?} out of range"))
+        };
+        let (min, max) = (temp(&mut data)?, temp(&mut data)?);
+        let sum = unzigzag(read_varint(&mut data)?);
+        let count = read_varint(&mut data)? as usize;
+        if count == 0 || min > max {
+            bail!("invalid sensor {name:?}");
+        }
+        let moments = match flags & MOMENTS {
+            0 => None,
+            _ => Some(Moments {
+                mean: read_f64(&mut data)?,
+                m2: read_f64(&mut data)?,
+                m3: read_f64(&mut data)?,
+                m4: read_f64(&mut data)?,
+            }),
+        };
+        let histogram = match flags & HISTOGRAMS {
+            0 => None,
+            _ => Some(
+                read_histogram(&mut data, min, max, count)
+                    .with_context(|| format!("invalid histogram of {name:?}"))?,
+            ),
+        };
+        let sketch = match flags & SKETCHES {
+            0 => None,
+            _ => Some(
+                read_sketch(&mut data, count)
+                    .with_context(|| format!("invalid sketch of {name:?}"))?,
+            ),
+        };
+        let sensor = Sensor::from_tenths(min, sum, count, max, moments, histogram, sketch);
+        sensors
+            .entry(name.to_owned())
+            .and_modify(|other: &mut Sensor| other.merge(&sensor))
+            .or_insert(sensor);
+    }
+    if !data.is_empty() {
+        bail!("{} trailing bytes after the partial aggregate", data.len());
+    }
+    Ok(Results::sorted(sensors, rejects))
+}
+
+/// Reads the histogram of a sensor, whose readings must add up to `count` within [`min`, `max`]
+fn read_histogram(data: &mut &[u8], min: i16, max: i16, count: usize) -> Result<Box<Histogram>> {
+    let mut histogram = Box::<Histogram>::default();
+    let mut temp = min as i64;
+    let mut total: u64 = 0;
+    for index in 0..read_varint(data)? {
+        let delta = read_varint(data)?;
+        if index > 0 && delta == 0 {
+            bail!("temperatures out of order");
+        }
+        temp = temp.saturating_add_unsigned(delta);
+        if temp > max as i64 || !(-999..=999).contains(&temp) {
+            bail!("temperature {temp} out of range");
+        }
+        let readings = read_varint(data)?;
+        total = total.saturating_add(readings);
+        histogram.add_temp_count(temp as i16, readings);
+    }
+    if total != count as u64 {
+        bail!("{total} readings instead of {count}");
+    }
+    Ok(histogram)
+}
+
+/// Reads the sketch of a sensor, whose readings must add up to `count`
+fn read_sketch(data: &mut &[u8], count: usize) -> Result<Box<Sketch>> {
+    let accuracy = read_f64(data)?;
+    let zeros = read_varint(data)?;
+    let mut buckets = || -> Result<Buckets> {
+        let len = read_varint(data)?;
+        let offset = unzigzag(read_varint(data)?);
+        let offset =
+            i32::try_from(offset).with_context(|| format!("bucket {offset} out of range"))?;
+        if len > MAX_BUCKETS as u64 || offset.checked_add(len as i32).is_none() {
+            bail!("{len} buckets from {offset}, at most {MAX_BUCKETS} expected");
+        }
+        let counts = (0..len)
+            .map(|_| read_varint(data))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Buckets::new(offset, counts))
+    };
+    let (positive, negative) = (buckets()?, buckets()?);
+    let Some(sketch) = Sketch::from_buckets(accuracy, zeros, positive, negative) else {
+        bail!("accuracy {accuracy} out of range");
+    };
+    let total = sketch.count();
+    if total != count as u64 {
+        bail!("{total} readings instead of {count}");
+    }
+    Ok(Box::new(sketch))
+}
+
+fn read_f64(data: &mut &[u8]) -> Result<f64> {
+    let Some((value, rest)) = data.split_first_chunk() else {
+        bail!("truncated partial aggregate");
+    };
+    *data = rest;
+    Ok(f64::from_le_bytes(*value))
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let Some((&byte, rest)) = data.split_first() else {
+            bail!("truncated partial aggregate");
+        };
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("invalid varint in the partial aggregate")
+}
+
+/// Maps the signed integers to unsigned ones, small in absolute value being small
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
@@ -0,0 +1,78 @@
+use std::cmp::Ordering;
+
+/// How the exact fixed-point values are rounded to the number of decimals they are written with.
+///
+/// Only ties are affected, the other values go to the nearest decimal. Zero is always written
+/// `0.0`, never `-0.0`.
+///
+/// ```
+/// use chunkit::Rounding;
+///
+/// // -0.05, -0.15, 0.05, 0.15 and 2.25 to one decimal
+/// let ties = [(-5, 100), (-15, 100), (5, 100), (15, 100), (225, 100)];
+/// let round = |rounding: Rounding| {
+///     ties.map(|(numerator, denominator)| rounding.format(numerator, denominator, 1).unwrap())
+/// };
+/// assert_eq!(round(Rounding::CeilHalf), ["0.0", "-0.1", "0.1", "0.2", "2.3"]);
+/// assert_eq!(round(Rounding::HalfEven), ["0.0", "-0.2", "0.0", "0.2", "2.2"]);
+/// assert_eq!(round(Rounding::HalfAwayFromZero), ["-0.1", "-0.2", "0.1", "0.2", "2.3"]);
+///
+/// // -0.04 is no tie but still rounds to zero
+/// assert_eq!(Rounding::HalfAwayFromZero.format(-4, 100, 1).unwrap(), "0.0");
+/// // the mean of -0.1 and 0.0 is -0.05
+/// assert_eq!(Rounding::CeilHalf.format(-1, 2 * 10, 1).unwrap(), "0.0");
+/// assert_eq!(Rounding::CeilHalf.format(-1, 3 * 10, 2).unwrap(), "-0.03");
+/// assert_eq!(Rounding::CeilHalf.format(-999, 10, 0).unwrap(), "-100");
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rounding {
+    /// Ties go toward positive infinity (`-0.05` is `0.0`, `0.05` is `0.1`), as mandated by the
+    /// 1BRC rules
+    #[default]
+    CeilHalf,
+    /// Ties go to the even neighbour (`0.05` is `0.0`, `0.15` is `0.2`)
+    HalfEven,
+    /// Ties go away from zero (`-0.05` is `-0.1`, `0.05` is `0.1`)
+    HalfAwayFromZero,
+}
+
+impl Rounding {
+    /// Rounds `numerator / denominator` to an integer, `denominator` must be positive
+    pub fn div(&self, numerator: i128, denominator: i128) -> i128 {
+        assert!(denominator > 0, "non positive denominator {denominator}");
+        let floor = numerator.div_euclid(denominator);
+        match (2 * numerator.rem_euclid(denominator)).cmp(&denominator) {
+            Ordering::Less => floor,
+            Ordering::Greater => floor + 1,
+            Ordering::Equal => match self {
+                Rounding::CeilHalf => floor + 1,
+                // adds one to odd floors only
+                Rounding::HalfEven => floor + (floor & 1),
+                Rounding::HalfAwayFromZero if numerator < 0 => floor,
+                Rounding::HalfAwayFromZero => floor + 1,
+            },
+        }
+    }
+
+    /// Writes `numerator / denominator` with `decimals` decimals.
+    ///
+    /// Returns `None` when `denominator` is not positive or the value does not fit an `i128`
+    /// once scaled.
+    pub fn format(&self, numerator: i128, denominator: i128, decimals: usize) -> Option<String> {
+        if denominator <= 0 {
+            return None;
+        }
+        let scale = 10i128.checked_pow(decimals.try_into().ok()?)?;
+        let value = self.div(numerator.checked_mul(scale)?, denominator);
+
+        let sign = if value < 0 { "-" } else { "" };
+        let (int, frac) = (
+            value.unsigned_abs() / scale as u128,
+            value.unsigned_abs() % scale as u128,
+        );
+        Some(match decimals {
+            0 => format!("{sign}{int}"),
+            _ => format!("{sign}{int}.{frac:0decimals$}"),
+        })
+    }
+}
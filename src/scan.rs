@@ -0,0 +1,198 @@
+/// Size of the blocks the delimiters are searched in, one bit per byte of the mask
+const BLOCK: usize = 64;
+
+/// Computes the mask of the first `BLOCK` bytes of a slice of at least `BLOCK` bytes
+type BlockMask = unsafe fn(&[u8], &[u8; 3]) -> u64;
+
+/// Iterates over the positions of the quotes, field delimiters and `\n` in `data`, in order.
+///
+/// The data is scanned `BLOCK` bytes at a time using the widest instruction set available at
+/// runtime (AVX2, then SSE2), with a scalar fallback for other targets and the trailing bytes.
+pub(crate) struct Delimiters<'a> {
+    data: &'a [u8],
+    /// The bytes to stop at: `"`, the field delimiter and `\n`
+    needles: [u8; 3],
+    /// Offset of the block `mask` refers to
+    offset: usize,
+    /// Bit `i` is set when `data[offset + i]` is a delimiter not yet yielded
+    mask: u64,
+    /// Offset of the next block to scan
+    next: usize,
+    block_mask: BlockMask,
+}
+
+impl<'a> Delimiters<'a> {
+    pub(crate) fn new(data: &'a [u8], delimiter: u8) -> Self {
+        Self {
+            data,
+            needles: [b'"', delimiter, b'\n'],
+            offset: 0,
+            mask: 0,
+            next: 0,
+            block_mask: detect(),
+        }
+    }
+}
+
+impl Iterator for Delimiters<'_> {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        while self.mask == 0 {
+            let rest = &self.data[self.next..];
+            if rest.is_empty() {
+                return None;
+            }
+            self.mask = if rest.len() >= BLOCK {
+                // SAFETY: `detect()` only picks implementations supported by the current cpu
+                // and `rest` holds at least `BLOCK` bytes
+                unsafe { (self.block_mask)(rest, &self.needles) }
+            } else {
+                scalar_mask(rest, &self.needles)
+            };
+            self.offset = self.next;
+            self.next += BLOCK.min(rest.len());
+        }
+        let index = self.mask.trailing_zeros() as usize;
+        // clear the lowest set bit
+        self.mask &= self.mask - 1;
+        Some(self.offset + index)
+    }
+}
+
+fn detect() -> BlockMask {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return x86::avx2_mask;
+        }
+        if is_x86_feature_detected!("sse2") {
+            return x86::sse2_mask;
+        }
+    }
+    scalar_block_mask
+}
+
+unsafe fn scalar_block_mask(data: &[u8], needles: &[u8; 3]) -> u64 {
+    scalar_mask(&data[..BLOCK], needles)
+}
+
+/// Mask of the needles in up to `BLOCK` bytes
+fn scalar_mask(data: &[u8], needles: &[u8; 3]) -> u64 {
+    data.iter()
+        .take(BLOCK)
+        .enumerate()
+        .filter(|(_, b)| needles.contains(b))
+        .fold(0, |mask, (index, _)| mask | (1 << index))
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    use super::BLOCK;
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn avx2_mask(data: &[u8], needles: &[u8; 3]) -> u64 {
+        debug_assert!(data.len() >= BLOCK);
+        let quote = _mm256_set1_epi8(needles[0] as i8);
+        let delimiter = _mm256_set1_epi8(needles[1] as i8);
+        let lf = _mm256_set1_epi8(needles[2] as i8);
+
+        let mut mask = 0;
+        for lane in 0..BLOCK / 32 {
+            let bytes = _mm256_loadu_si256(data.as_ptr().add(lane * 32) as *const __m256i);
+            let hits = _mm256_or_si256(
+                _mm256_or_si256(
+                    _mm256_cmpeq_epi8(bytes, quote),
+                    _mm256_cmpeq_epi8(bytes, delimiter),
+                ),
+                _mm256_cmpeq_epi8(bytes, lf),
+            );
+            mask |= (_mm256_movemask_epi8(hits) as u32 as u64) << (lane * 32);
+        }
+        mask
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn sse2_mask(data: &[u8], needles: &[u8; 3]) -> u64 {
+        debug_assert!(data.len() >= BLOCK);
+        let quote = _mm_set1_epi8(needles[0] as i8);
+        let delimiter = _mm_set1_epi8(needles[1] as i8);
+        let lf = _mm_set1_epi8(needles[2] as i8);
+
+        let mut mask = 0;
+        for lane in 0..BLOCK / 16 {
+            let bytes = _mm_loadu_si128(data.as_ptr().add(lane * 16) as *const __m128i);
+            let hits = _mm_or_si128(
+                _mm_or_si128(
+                    _mm_cmpeq_epi8(bytes, quote),
+                    _mm_cmpeq_epi8(bytes, delimiter),
+                ),
+                _mm_cmpeq_epi8(bytes, lf),
+            );
+            mask |= (_mm_movemask_epi8(hits) as u16 as u64) << (lane * 16);
+        }
+        mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The implementations of `BlockMask` supported by the current cpu
+    fn block_masks() -> Vec<(&'static str, BlockMask)> {
+        #[allow(unused_mut)]
+        let mut masks: Vec<(_, BlockMask)> = vec![("scalar", scalar_block_mask)];
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("avx2") {
+                masks.push(("avx2", x86::avx2_mask));
+            }
+            if is_x86_feature_detected!("sse2") {
+                masks.push(("sse2", x86::sse2_mask));
+            }
+        }
+        masks
+    }
+
+    #[test]
+    fn block_masks_match_a_naive_scan() {
+        for delimiter in [b';', b',', b'\t', 0xFF] {
+            let needles = [b'"', delimiter, b'\n'];
+            for len in 0..=200 {
+                // needles around the lanes of the blocks, and bytes that only differ by their sign
+                let mut data = vec![b'a'; len];
+                for (index, b) in data.iter_mut().enumerate() {
+                    *b = match index % 67 {
+                        31 | 63 => b'"',
+                        32 | 64 => delimiter,
+                        0 | 15 | 16 => b'\n',
+                        40 => delimiter ^ 0x80,
+                        _ if index % 5 == 0 => 0x80 | (index as u8),
+                        _ => b'a',
+                    };
+                }
+                let expected: Vec<_> = (0..len).filter(|&i| needles.contains(&data[i])).collect();
+                for (name, block_mask) in block_masks() {
+                    let mut delimiters = Delimiters::new(&data, delimiter);
+                    delimiters.block_mask = block_mask;
+                    let found: Vec<_> = delimiters.collect();
+                    assert_eq!(found, expected, "{name}, {len} bytes, {delimiter:?}");
+
+                    for start in 0..len.saturating_sub(BLOCK - 1) {
+                        let block = &data[start..];
+                        // SAFETY: only the implementations supported by the cpu are tested
+                        let mask = unsafe { block_mask(block, &needles) };
+                        assert_eq!(mask, scalar_mask(block, &needles), "{name} at {start}");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use crate::histogram::Histogram;
+use crate::moments::Moments;
+use crate::sketch::Sketch;
+use crate::Rounding;
+
+/// The aggregated readings of a station
+///
+/// Temperatures are kept in tenths of degree (`-12.3` is `-123`) so that the sum stays exact,
+/// they are only converted to decimals when read.
+///
+/// The variance, standard deviation, skewness and kurtosis are only tracked when asked to, see
+/// [`Aggregator::moments`](crate::Aggregator::moments), and the percentiles when asked to with
+/// [`Aggregator::histograms`](crate::Aggregator::histograms) or
+/// [`Aggregator::sketches`](crate::Aggregator::sketches).
+///
+/// ```
+/// use chunkit::Aggregator;
+///
+/// let data = b"Abha;1.0\nAbha;2.0\nAbha;3.0\nAbha;6.0\n";
+/// let results = Aggregator::new().moments(true).histograms(true).run(data)?;
+/// let abha = results.get("Abha").unwrap();
+/// assert_eq!((abha.min(), abha.mean(), abha.max()), (1.0, 3.0, 6.0));
+/// assert_eq!(abha.variance(), Some(3.5));
+/// assert_eq!(abha.median(), Some(2.5));
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct Sensor {
+    min: i16,
+    sum: i64,
+    cnt: usize,
+    max: i16,
+    moments: Option<Moments>,
+    histogram: Option<Box<Histogram>>,
+    sketch: Option<Box<Sketch>>,
+}
+
+/// The optional statistics tracked by the sensors, on top of the min, mean and max
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Tracking {
+    pub(crate) moments: bool,
+    pub(crate) histogram: bool,
+    /// The relative accuracy of the sketches, if any
+    pub(crate) sketch: Option<f64>,
+}
+
+/// The optional statistics of a station, as asked for by a [`Tracking`]
+#[derive(Debug, Default)]
+pub(crate) struct Tracked {
+    pub(crate) moments: Option<Moments>,
+    pub(crate) histogram: Option<Box<Histogram>>,
+    pub(crate) sketch: Option<Box<Sketch>>,
+}
+
+impl Sensor {
+    /// The lowest temperature seen
+    pub fn min(&self) -> f64 {
+        self.min as f64 / 10.0
+    }
+
+    /// The average of all the temperatures seen
+    pub fn mean(&self) -> f64 {
+        self.sum as f64 / self.cnt as f64 / 10.0
+    }
+
+    /// The highest temperature seen
+    pub fn max(&self) -> f64 {
+        self.max as f64 / 10.0
+    }
+
+    /// The number of readings
+    pub fn count(&self) -> usize {
+        self.cnt
+    }
+
+    /// The lowest temperature seen, in tenths of degree
+    pub fn min_tenths(&self) -> i16 {
+        self.min
+    }
+
+    /// The sum of all the temperatures seen, in tenths of degree
+    pub fn sum_tenths(&self) -> i64 {
+        self.sum
+    }
+
+    /// The highest temperature seen, in tenths of degree
+    pub fn max_tenths(&self) -> i16 {
+        self.max
+    }
+
+    /// The population variance of the readings, `None` unless tracked
+    pub fn variance(&self) -> Option<f64> {
+        let moments = self.moments.as_ref()?;
+        Some(moments.m2 / self.cnt as f64 / 100.0)
+    }
+
+    /// The population standard deviation of the readings, `None` unless tracked
+    pub fn std_dev(&self) -> Option<f64> {
+        self.variance().map(f64::sqrt)
+    }
+
+    /// The population skewness of the readings, `None` unless tracked or when all the readings are
+    /// equal
+    pub fn skewness(&self) -> Option<f64> {
+        let moments = self.moments.as_ref().filter(|moments| moments.m2 > 0.0)?;
+        Some((self.cnt as f64).sqrt() * moments.m3 / moments.m2.powf(1.5))
+    }
+
+    /// The excess kurtosis of the readings (0 for a normal distribution), `None` unless tracked or
+    /// when all the readings are equal
+    pub fn kurtosis(&self) -> Option<f64> {
+        let moments = self.moments.as_ref().filter(|moments| moments.m2 > 0.0)?;
+        Some(self.cnt as f64 * moments.m4 / (moments.m2 * moments.m2) - 3.0)
+    }
+
+    /// The `p`-th percentile of the readings (`p` in [0, 100]), `None` unless tracked.
+    ///
+    /// With the histograms, it is exact, interpolated linearly between the two closest readings
+    /// when it falls between them, like the default method of NumPy or R. With a sketch only, it
+    /// is the reading of rank `floor(p / 100 * (count - 1))` within the accuracy of the sketch.
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        match (&self.histogram, &self.sketch) {
+            (Some(histogram), _) => Some(histogram.quantile(self.cnt, p / 100.0)? / 10.0),
+            (None, Some(sketch)) => sketch.quantile(p / 100.0),
+            (None, None) => None,
+        }
+    }
+
+    /// The median of the readings, `None` unless tracked
+    pub fn median(&self) -> Option<f64> {
+        self.percentile(50.0)
+    }
+
+    /// Writes the min, mean and max with `decimals` decimals, rounding their exact values.
+    ///
+    /// Falls back to the `f64` values when they cannot be rounded exactly (eg. without readings).
+    pub fn format(&self, rounding: Rounding, decimals: usize) -> [String; 3] {
+        let exact = |numerator: i64, denominator: usize, approx: f64| {
+            rounding
+                .format(numerator as i128, denominator as i128 * 10, decimals)
+                .unwrap_or_else(|| format!("{approx:.decimals$}"))
+        };
+        [
+            exact(self.min as i64, 1, self.min()),
+            exact(self.sum, self.cnt, self.mean()),
+            exact(self.max as i64, 1, self.max()),
+        ]
+    }
+
+    /// A sensor from its exact state, eg. read from a partial aggregate
+    pub(crate) fn from_tenths(
+        min: i16,
+        sum: i64,
+        cnt: usize,
+        max: i16,
+        moments: Option<Moments>,
+        histogram: Option<Box<Histogram>>,
+        sketch: Option<Box<Sketch>>,
+    ) -> Self {
+        Self {
+            min,
+            sum,
+            cnt,
+            max,
+            moments,
+            histogram,
+            sketch,
+        }
+    }
+
+    /// The central moments, if tracked
+    pub(crate) fn moments(&self) -> Option<&Moments> {
+        self.moments.as_ref()
+    }
+
+    /// The number of readings of each temperature, if tracked
+    pub(crate) fn histogram(&self) -> Option<&Histogram> {
+        self.histogram.as_deref()
+    }
+
+    /// The sketch of the readings, if tracked
+    pub(crate) fn sketch(&self) -> Option<&Sketch> {
+        self.sketch.as_deref()
+    }
+
+    pub fn merge(&mut self, sensor: &Sensor) {
+        // the moments of an empty sensor do not matter, those of the other one are kept
+        self.moments = match (self.moments, sensor.moments) {
+            (Some(moments), Some(other)) => Some(moments.merge(self.cnt, &other, sensor.cnt)),
+            (moments, _) if sensor.cnt == 0 => moments,
+            (_, other) if self.cnt == 0 => other,
+            _ => None,
+        };
+        match (&mut self.histogram, &sensor.histogram) {
+            (Some(histogram), Some(other)) => histogram.merge(other),
+            _ if sensor.cnt == 0 => {}
+            (histogram, other) if self.cnt == 0 => histogram.clone_from(other),
+            (histogram, _) => *histogram = None,
+        }
+        match (&mut self.sketch, &sensor.sketch) {
+            (Some(sketch), Some(other)) if sketch.accuracy() == other.accuracy() => {
+                sketch.merge(other)
+            }
+            _ if sensor.cnt == 0 => {}
+            (sketch, other) if self.cnt == 0 => sketch.clone_from(other),
+            (sketch, _) => *sketch = None,
+        }
+        if self.min > sensor.min {
+            self.min = sensor.min;
+        }
+        if self.max < sensor.max {
+            self.max = sensor.max;
+        }
+        self.sum += sensor.sum;
+        self.cnt += sensor.cnt;
+    }
+}
+
+impl Tracked {
+    pub(crate) fn new(tracking: Tracking) -> Self {
+        Self {
+            moments: tracking.moments.then(Moments::default),
+            histogram: tracking.histogram.then(Box::default),
+            sketch: tracking
+                .sketch
+                .map(|accuracy| Box::new(Sketch::new(accuracy))),
+        }
+    }
+
+    /// Adds the reading `temp` to the `count` ones seen so far, kept out of line so that it does
+    /// not slow down the aggregations without any tracking
+    #[inline(never)]
+    pub(crate) fn add(&mut self, count: usize, temp: i16) {
+        if let Some(moments) = &mut self.moments {
+            moments.add(count, temp);
+        }
+        if let Some(histogram) = &mut self.histogram {
+            histogram.add(temp);
+        }
+        if let Some(sketch) = &mut self.sketch {
+            sketch.add(temp as f64 / 10.0);
+        }
+    }
+}
+
+impl Default for Sensor {
+    fn default() -> Self {
+        Self {
+            min: i16::MAX,
+            sum: 0,
+            cnt: 0,
+            max: i16::MIN,
+            moments: None,
+            histogram: None,
+            sketch: None,
+        }
+    }
+}
+
+impl serde::Serialize for Sensor {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let len = if self.moments.is_some() { 8 } else { 4 };
+        let mut s = serializer.serialize_struct("Sensor", len)?;
+        s.serialize_field("min", &self.min())?;
+        s.serialize_field("avg", &self.mean())?;
+        s.serialize_field("max", &self.max())?;
+        s.serialize_field("count", &self.cnt)?;
+        if self.moments.is_some() {
+            s.serialize_field("variance", &self.variance())?;
+            s.serialize_field("stddev", &self.std_dev())?;
+            s.serialize_field("skewness", &self.skewness())?;
+            s.serialize_field("kurtosis", &self.kurtosis())?;
+        }
+        s.end()
+    }
+}
+
+/// Parses a temperature with exactly one decimal digit (eg. `-12.3`) into tenths of degree
+/// (`-123`).
+///
+/// The values too large for an `i16` saturate, so that a well-formed `1000000.0` is out of range
+/// rather than invalid.
+pub(crate) fn parse_temp(text: &[u8]) -> Option<i16> {
+    let (negative, digits) = match text {
+        [b'-', rest @ ..] => (true, rest),
+        _ => (false, text),
+    };
+    let [int @ .., b'.', dec] = digits else {
+        return None;
+    };
+    if int.is_empty() {
+        return None;
+    }
+
+    let mut value: i16 = 0;
+    for &digit in int.iter().chain(std::iter::once(dec)) {
+        if !digit.is_ascii_digit() {
+            return None;
+        }
+        value = value
+            .saturating_mul(10)
+            .saturating_add((digit - b'0') as i16);
+    }
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Aggregator;
+
+    #[test]
+    fn parse_temps() {
+        for (text, temp) in [
+            ("0.0", 0),
+            ("-0.0", 0),
+            ("1.2", 12),
+            ("-1.2", -12),
+            ("12.3", 123),
+            ("-99.9", -999),
+            ("99.9", 999),
+            ("007.5", 75),
+            ("100.0", 1000),
+            ("1000000.0", i16::MAX),
+            ("-1000000.0", -i16::MAX),
+        ] {
+            assert_eq!(parse_temp(text.as_bytes()), Some(temp), "{text}");
+        }
+        for text in [
+            "", "1", "1.", ".1", "-.1", "1.23", "1,2", "+1.2", "--1.2", "1.x", "a.1", " 1.2",
+        ] {
+            assert_eq!(parse_temp(text.as_bytes()), None, "{text:?}");
+        }
+    }
+
+    fn abha(aggregator: Aggregator, data: &[u8]) -> Sensor {
+        aggregator.run(data).unwrap().get("Abha").unwrap().clone()
+    }
+
+    #[test]
+    fn moments() {
+        let data = b"Abha;1.0\nAbha;2.0\nAbha;3.0\nAbha;6.0\n";
+        assert_eq!(abha(Aggregator::new(), data).variance(), None);
+
+        for chunk_size in 1..=data.len() + 1 {
+            let aggregator = Aggregator::new()
+                .moments(true)
+                .chunk_size(chunk_size)
+                .threads(3);
+            let abha = abha(aggregator, data);
+            assert!((abha.variance().unwrap() - 3.5).abs() < 1e-9);
+            assert!((abha.std_dev().unwrap() - 3.5f64.sqrt()).abs() < 1e-9);
+            assert!((abha.skewness().unwrap() - 0.6872).abs() < 1e-4);
+            assert!((abha.kurtosis().unwrap() + 1.0).abs() < 1e-9);
+        }
+
+        let constant = abha(Aggregator::new().moments(true), b"Abha;1.0\nAbha;1.0\n");
+        assert_eq!(constant.variance(), Some(0.0));
+        assert_eq!(constant.skewness(), None);
+    }
+
+    #[test]
+    fn percentiles() {
+        let data = b"Abha;1.0\nAbha;2.0\nAbha;3.0\nAbha;6.0\n";
+        assert_eq!(abha(Aggregator::new(), data).median(), None);
+
+        for chunk_size in 1..=data.len() + 1 {
+            let aggregator = Aggregator::new().histograms(true).chunk_size(chunk_size);
+            let abha = abha(aggregator, data);
+            assert_eq!(abha.median(), Some(2.5));
+            assert_eq!(abha.percentile(0.0), Some(1.0));
+            assert_eq!(abha.percentile(75.0), Some(3.75));
+            assert_eq!(abha.percentile(100.0), Some(6.0));
+            assert_eq!(abha.percentile(101.0), None);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+/// The bounds of the relative accuracy of the sketches
+const MIN_ACCURACY: f64 = 0.0001;
+const MAX_ACCURACY: f64 = 0.5;
+
+/// The most buckets kept for each sign, the ones of the smallest magnitudes are collapsed past it
+pub(crate) const MAX_BUCKETS: usize = 2048;
+
+/// A DDSketch of the readings of a station, estimating their quantiles within a relative error.
+///
+/// The readings are counted in buckets whose bounds grow geometrically by
+/// `gamma = (1 + accuracy) / (1 - accuracy)`, so that the middle of the bucket of a reading is
+/// within `accuracy` of it in relative terms, whatever its magnitude. Unlike the
+/// [`Histogram`](crate::histogram::Histogram), it would hold any value, but the readings are only
+/// one decimal temperatures for now.
+///
+/// Two sketches of the same accuracy merge by adding their buckets, which gives exactly the
+/// sketch of all the readings: unlike the sampling sketches (KLL, t-digest), the estimates do not
+/// depend on how the readings are split among the workers.
+///
+/// Each sign keeps at most `MAX_BUCKETS` buckets, enough for 17 orders of magnitude at 1%, so a
+/// sketch never takes more than 32 KiB. Past them, the buckets of the smallest magnitudes are
+/// collapsed together and lose their accuracy.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Sketch {
+    accuracy: f64,
+    /// `ln(gamma)`, the width of the buckets on a log scale
+    ln_gamma: f64,
+    /// The readings too close to zero to be indexed
+    zeros: u64,
+    positive: Buckets,
+    /// The buckets of the absolute values of the negative readings
+    negative: Buckets,
+}
+
+/// Counts indexed by a contiguous range of bucket indices
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Buckets {
+    /// The index of the first bucket
+    offset: i32,
+    counts: Vec<u64>,
+}
+
+impl Sketch {
+    /// An empty sketch, `accuracy` being clamped to [`MIN_ACCURACY`, `MAX_ACCURACY`]
+    pub(crate) fn new(accuracy: f64) -> Self {
+        let accuracy = accuracy.clamp(MIN_ACCURACY, MAX_ACCURACY);
+        Self {
+            accuracy,
+            ln_gamma: ((1.0 + accuracy) / (1.0 - accuracy)).ln(),
+            zeros: 0,
+            positive: Buckets::default(),
+            negative: Buckets::default(),
+        }
+    }
+
+    /// A sketch from its buckets, eg. read from a partial aggregate. `None` when the accuracy is
+    /// out of bounds or there are too many buckets
+    pub(crate) fn from_buckets(
+        accuracy: f64,
+        zeros: u64,
+        positive: Buckets,
+        negative: Buckets,
+    ) -> Option<Self> {
+        if !(MIN_ACCURACY..=MAX_ACCURACY).contains(&accuracy)
+            || positive.counts.len() > MAX_BUCKETS
+            || negative.counts.len() > MAX_BUCKETS
+        {
+            return None;
+        }
+        Some(Self {
+            zeros,
+            positive,
+            negative,
+            ..Self::new(accuracy)
+        })
+    }
+
+    pub(crate) fn accuracy(&self) -> f64 {
+        self.accuracy
+    }
+
+    pub(crate) fn zeros(&self) -> u64 {
+        self.zeros
+    }
+
+    /// The buckets of the positive readings, then those of the negative ones
+    pub(crate) fn buckets(&self) -> [&Buckets; 2] {
+        [&self.positive, &self.negative]
+    }
+
+    /// The number of readings
+    pub(crate) fn count(&self) -> u64 {
+        self.zeros + self.positive.count() + self.negative.count()
+    }
+
+    #[inline]
+    pub(crate) fn add(&mut self, value: f64) {
+        if value.abs() < f64::MIN_POSITIVE {
+            self.zeros += 1;
+        } else if value > 0.0 {
+            self.positive.add(self.index(value), 1);
+        } else {
+            self.negative.add(self.index(-value), 1);
+        }
+    }
+
+    /// Adds the readings of `other`, which must have the same accuracy
+    pub(crate) fn merge(&mut self, other: &Sketch) {
+        debug_assert_eq!(self.accuracy, other.accuracy);
+        self.zeros += other.zeros;
+        self.positive.merge(&other.positive);
+        self.negative.merge(&other.negative);
+    }
+
+    /// An estimate of the `q`-quantile of the readings (`q` in [0, 1]), the reading of rank
+    /// `floor(q * (count - 1))` within the relative accuracy of the sketch
+    pub(crate) fn quantile(&self, q: f64) -> Option<f64> {
+        let count = self.count();
+        if count == 0 || !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+        let rank = (q * (count - 1) as f64) as u64;
+
+        // in increasing order, the negative readings of decreasing magnitudes come first
+        let negative = self.negative.iter().rev().map(|(index, count)| {
+            let value = -self.value(index);
+            (value, count)
+        });
+        let zeros = std::iter::once((0.0, self.zeros));
+        let positive = self
+            .positive
+            .iter()
+            .map(|(index, count)| (self.value(index), count));
+        let mut seen = 0;
+        negative
+            .chain(zeros)
+            .chain(positive)
+            .find_map(|(value, count)| {
+                seen += count;
+                (seen > rank).then_some(value)
+            })
+    }
+
+    /// The bucket of the positive `value`, covering `(gamma^(index - 1), gamma^index]`
+    #[inline]
+    fn index(&self, value: f64) -> i32 {
+        (value.ln() / self.ln_gamma).ceil() as i32
+    }
+
+    /// The value of the middle of the bucket `index`, within `accuracy` of both its bounds
+    fn value(&self, index: i32) -> f64 {
+        let gamma = self.ln_gamma.exp();
+        (index as f64 * self.ln_gamma).exp() * 2.0 / (1.0 + gamma)
+    }
+}
+
+impl Buckets {
+    /// Buckets from their counts, the first one being the bucket `offset`
+    pub(crate) fn new(offset: i32, counts: Vec<u64>) -> Self {
+        Self { offset, counts }
+    }
+
+    pub(crate) fn offset(&self) -> i32 {
+        self.offset
+    }
+
+    pub(crate) fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The non empty buckets, in increasing index order
+    fn iter(&self) -> impl DoubleEndedIterator<Item = (i32, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(bucket, &count)| (self.offset + bucket as i32, count))
+    }
+
+    #[inline]
+    fn add(&mut self, index: i32, count: u64) {
+        match index
+            .checked_sub(self.offset)
+            .and_then(|bucket| self.counts.get_mut(usize::try_from(bucket).ok()?))
+        {
+            Some(counts) => *counts += count,
+            None => self.extend(index, count),
+        }
+    }
+
+    fn merge(&mut self, other: &Buckets) {
+        for (index, count) in other.iter() {
+            self.add(index, count);
+        }
+    }
+
+    /// Adds a bucket out of the current range, collapsing the lowest ones past `MAX_BUCKETS`
+    #[cold]
+    fn extend(&mut self, index: i32, count: u64) {
+        let (low, high) = match self.counts.is_empty() {
+            true => (index, index),
+            false => {
+                let high = self.offset + self.counts.len() as i32 - 1;
+                (self.offset.min(index), high.max(index))
+            }
+        };
+        let low = low.max(high - MAX_BUCKETS as i32 + 1);
+
+        let mut counts = vec![0; (high - low + 1) as usize];
+        for (index, count) in self.iter().chain(std::iter::once((index, count))) {
+            counts[(index.max(low) - low) as usize] += count;
+        }
+        *self = Self::new(low, counts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantiles_of_any_magnitude() {
+        let accuracy = 0.01;
+        let mut sketch = Sketch::new(accuracy);
+        let mut values: Vec<f64> = (-300..=300)
+            .map(|exponent| exponent as f64 / 20.0)
+            .map(|exponent| exponent.signum() * 10f64.powf(exponent.abs() - 5.0))
+            .collect();
+        values.push(0.0);
+        for &value in &values {
+            sketch.add(value);
+        }
+        values.sort_by(f64::total_cmp);
+        assert_eq!(sketch.count(), values.len() as u64);
+        for q in [0.0, 0.1, 0.25, 0.5, 0.75, 0.9, 1.0] {
+            let exact = values[(q * (values.len() - 1) as f64) as usize];
+            let estimate = sketch.quantile(q).unwrap();
+            assert!((estimate - exact).abs() <= accuracy * exact.abs(), "q{q}");
+        }
+        assert_eq!(sketch.quantile(1.5), None);
+        assert_eq!(Sketch::new(accuracy).quantile(0.5), None);
+    }
+
+    #[test]
+    fn merges_into_the_sketch_of_all_readings() {
+        let values: Vec<f64> = (0..1000).map(|i| (i as f64 * 0.37).sin() * 50.0).collect();
+        let mut whole = Sketch::new(0.02);
+        values.iter().for_each(|&value| whole.add(value));
+
+        let mut merged = Sketch::new(0.02);
+        for chunk in values.chunks(77) {
+            let mut part = Sketch::new(0.02);
+            chunk.iter().for_each(|&value| part.add(value));
+            merged.merge(&part);
+        }
+        assert_eq!(merged, whole);
+    }
+
+    #[test]
+    fn collapses_the_smallest_buckets() {
+        let mut sketch = Sketch::new(0.0001);
+        // a value within each bucket, past the limit
+        let values: Vec<f64> = (0..MAX_BUCKETS + 100)
+            .map(|i| ((i as f64 - 0.5) * sketch.ln_gamma).exp())
+            .collect();
+        values.iter().for_each(|&value| sketch.add(value));
+        assert_eq!(sketch.positive.counts.len(), MAX_BUCKETS);
+        assert_eq!(sketch.count(), values.len() as u64);
+        // the largest readings keep their accuracy, the smallest ones do not
+        let max = sketch.quantile(1.0).unwrap();
+        assert!((max - values[values.len() - 1]).abs() <= 0.0001 * max);
+        assert!(sketch.quantile(0.0).unwrap() > values[1]);
+    }
+}
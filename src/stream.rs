@@ -0,0 +1,256 @@
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread::ScopedJoinHandle;
+use std::time::Instant;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::aggregator::{merge_table, process_chunk, HashMap};
+use crate::chunk::{Quotes, Quoting};
+use crate::sensor::Tracking;
+use crate::table::StationTable;
+use crate::{Chunk, Format, OnError, ParseError, Rejects, Results};
+
+/// Whole records read from a stream
+struct Batch {
+    data: Vec<u8>,
+    /// Absolute offset of `data` in the stream
+    start: usize,
+    /// 1-based number of the line `data` starts on
+    line: usize,
+}
+
+/// Aggregates the records read from `reader` on `nb_threads` workers.
+///
+/// The calling thread fills buffers of about `buffer_size` bytes and cuts each of them after its
+/// last record, the partial record that follows is carried over to the next buffer. The buffers
+/// go to the workers through a queue bounded to `nb_threads` and come back once processed, so the
+/// memory use stays around `2 * nb_threads` buffers whatever the size of the stream. A buffer that
+/// holds no complete record grows until it does.
+///
+/// As with a mapped input, a failing worker stops the reading and the error returned is the first
+/// one of the stream.
+pub(crate) fn process_stream(
+    reader: &mut dyn Read,
+    format: &Format,
+    on_error: OnError,
+    tracking: Tracking,
+    nb_threads: usize,
+    buffer_size: usize,
+    verbose: bool,
+) -> Result<Results> {
+    format.check()?;
+    let (batches, queue) = mpsc::sync_channel::<Batch>(nb_threads);
+    let (free, buffers) = mpsc::channel::<Vec<u8>>();
+    let queue = Mutex::new(queue);
+    let failed = AtomicBool::new(false);
+
+    let (read, workers) = std::thread::scope(|ctx| {
+        let handles = (0..nb_threads)
+            .map(|_| {
+                let free = free.clone();
+                let (queue, failed) = (&queue, &failed);
+                ctx.spawn(move || {
+                    let start = Instant::now();
+                    let tid = std::thread::current().id();
+                    let mut sensors = HashMap::default();
+                    let mut rejects = Rejects::default();
+                    let mut error = None;
+                    let mut nb_batches = 0;
+                    loop {
+                        let batch = match queue.lock() {
+                            Ok(queue) => queue.recv(),
+                            Err(_) => break,
+                        };
+                        let Ok(batch) = batch else {
+                            break;
+                        };
+                        // keep draining the queue after an error so the reader is never stuck
+                        if error.is_none() {
+                            nb_batches += 1;
+                            match process_batch(&batch, format, on_error, tracking, &mut rejects) {
+                                Ok(table) => merge_table(&mut sensors, table),
+                                Err(err) => {
+                                    failed.store(true, Ordering::Relaxed);
+                                    error =
+                                        Some(err.locate_in(&batch.data, batch.start, batch.line));
+                                }
+                            }
+                        }
+                        // the reader may be gone already
+                        let _ = free.send(batch.data);
+                    }
+                    if verbose {
+                        eprintln!(
+                            "{tid:?} took {:?} for {nb_batches} buffers",
+                            start.elapsed()
+                        );
+                    }
+                    match error {
+                        Some(err) => Err(err),
+                        None => Ok((sensors, rejects)),
+                    }
+                })
+            })
+            .collect::<Vec<ScopedJoinHandle<Result<(HashMap, Rejects), ParseError>>>>();
+        drop(free);
+
+        // closes the queue once done, which stops the workers
+        let read = read_batches(
+            reader,
+            format,
+            buffer_size,
+            batches,
+            buffers,
+            &failed,
+            verbose,
+        );
+        let workers = handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .map_err(|err| anyhow!("unable to join the thread ({err:?})"))
+            })
+            .collect::<Result<Vec<_>>>();
+        (read, workers)
+    });
+
+    let mut all_sensors = HashMap::default();
+    let mut all_rejects = Rejects::default();
+    let mut first: Option<ParseError> = None;
+    for worker in workers? {
+        match worker {
+            Ok((sensors, rejects)) => {
+                all_rejects.merge(rejects);
+                for (name, s) in sensors {
+                    all_sensors
+                        .entry(name)
+                        .and_modify(|sensor| sensor.merge(&s))
+                        .or_insert(s);
+                }
+            }
+            Err(err) => {
+                if first.as_ref().is_none_or(|first| err.offset < first.offset) {
+                    first = Some(err);
+                }
+            }
+        }
+    }
+    if let Some(err) = first {
+        return Err(err.into());
+    }
+    read?;
+    Ok(Results::sorted(all_sensors, all_rejects))
+}
+
+fn process_batch<'a>(
+    batch: &'a Batch,
+    format: &Format,
+    on_error: OnError,
+    tracking: Tracking,
+    rejects: &mut Rejects,
+) -> Result<StationTable<'a>, ParseError> {
+    let chunk = Chunk {
+        data: &batch.data,
+        start: batch.start,
+        end: batch.start + batch.data.len(),
+        file: 0,
+    };
+    let mut sensors = StationTable::new(format.utf8, tracking);
+    process_chunk(&chunk, format, on_error, &mut sensors, rejects)?;
+    Ok(sensors)
+}
+
+/// Reads `reader` to the end (or until `failed`), sending its records to `batches` in buffers
+/// that are reused once they come back from `buffers`
+fn read_batches(
+    reader: &mut dyn Read,
+    format: &Format,
+    buffer_size: usize,
+    batches: SyncSender<Batch>,
+    buffers: Receiver<Vec<u8>>,
+    failed: &AtomicBool,
+    verbose: bool,
+) -> Result<()> {
+    let start_time = Instant::now();
+    let mut nb_batches = 0;
+    let mut buf = Vec::with_capacity(buffer_size);
+    let mut start = 0;
+    let mut line = 1;
+    let mut first = true;
+    while !failed.load(Ordering::Relaxed) {
+        let spare = buf.capacity() - buf.len();
+        let read = Read::take(&mut *reader, spare as u64)
+            .read_to_end(&mut buf)
+            .context("unable to read the input")?;
+        let eof = read < spare;
+
+        // only the first buffer may start with a BOM, the others start with a record
+        let base = if first { Format::bom_len(&buf) } else { 0 };
+        let cut = match last_record_end(&buf, base, format.delimiter) {
+            // the last record may not be terminated
+            _ if eof => buf.len(),
+            Some(lf) => lf + 1,
+            None => {
+                buf.reserve(buf.capacity());
+                continue;
+            }
+        };
+        let mut next = buffers.try_recv().unwrap_or_default();
+        next.clear();
+        next.reserve(buffer_size.max(buf.len() - cut));
+        next.extend_from_slice(&buf[cut..]);
+        buf.truncate(cut);
+
+        if first {
+            first = false;
+            let base = format.body_start(&buf);
+            line += count(&buf[..base], b'\n');
+            start += base;
+            buf.drain(..base);
+        }
+        let (len, lines) = (buf.len(), count(&buf, b'\n'));
+        if len > 0 {
+            nb_batches += 1;
+            batches
+                .send(Batch {
+                    data: buf,
+                    start,
+                    line,
+                })
+                .map_err(|_| anyhow!("the workers stopped"))?;
+        }
+        start += len;
+        line += lines;
+        buf = next;
+        if eof {
+            break;
+        }
+    }
+    if verbose {
+        eprintln!("read {nb_batches} buffers in {:?}", start_time.elapsed());
+    }
+    Ok(())
+}
+
+/// Returns the index of the last LF in `data` that terminates a record, `data[base..]` starting
+/// with one
+fn last_record_end(data: &[u8], base: usize, delimiter: u8) -> Option<usize> {
+    let quotes = Quotes::new(data, base, delimiter);
+    let mut state = Quoting::Unquoted;
+    let mut end = None;
+    for (index, &b) in data.iter().enumerate().skip(base) {
+        if b == b'\n' && state != Quoting::Quoted {
+            end = Some(index);
+        }
+        state = quotes.next(state, index);
+    }
+    end
+}
+
+fn count(data: &[u8], needle: u8) -> usize {
+    data.iter().filter(|&&b| b == needle).count()
+}
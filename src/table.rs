@@ -0,0 +1,310 @@
+use std::borrow::Cow;
+
+use crate::sensor::{Tracked, Tracking};
+use crate::{Sensor, Utf8};
+
+/// Names up to this many bytes are also stored inline in their slot, so that probing only
+/// compares two words instead of following the pointer to the input
+const INLINE: usize = 16;
+
+/// Starting number of slots, the 413 stations of the 1BRC data only fill 10% of them which keeps
+/// the probe sequences (and the branch mispredictions they cause) short
+const INITIAL_CAPACITY: usize = 4096;
+
+/// Open-addressing hash table specialized for aggregating readings per station.
+///
+/// The number of slots is a power of two and collisions are resolved with linear probing. The
+/// table doubles as soon as it gets half full, so it keeps up with the 10k distinct stations
+/// allowed by the 1BRC rules (and more). The hash of a name is computed by the caller, while
+/// scanning the row, when building its [`Key`].
+///
+/// The optional statistics asked for by the `tracking` are kept in a side table, so that a slot
+/// fits a cache line whatever is tracked.
+pub(crate) struct StationTable<'a> {
+    slots: Vec<Option<Slot<'a>>>,
+    utf8: Utf8,
+    /// What the sensors track on top of the min, mean and max
+    tracking: Tracking,
+    /// The tracked statistics of the stations, in insertion order. Empty when nothing is tracked
+    tracked: Vec<Tracked>,
+    len: usize,
+    /// `64 - log2(slots.len())`, the hash high bits are used as the slot index
+    shift: u32,
+}
+
+struct Slot<'a> {
+    key: Key<'a>,
+    readings: Readings,
+}
+
+/// The part of a [`Sensor`] updated by every reading
+struct Readings {
+    min: i16,
+    max: i16,
+    /// The index of the station in `StationTable::tracked`, when anything is tracked
+    index: u32,
+    sum: i64,
+    cnt: usize,
+}
+
+impl<'a> StationTable<'a> {
+    pub(crate) fn new(utf8: Utf8, tracking: Tracking) -> Self {
+        Self::with_capacity(INITIAL_CAPACITY, utf8, tracking)
+    }
+
+    fn with_capacity(capacity: usize, utf8: Utf8, tracking: Tracking) -> Self {
+        let capacity = capacity.next_power_of_two();
+        let mut slots = Vec::with_capacity(capacity);
+        slots.resize_with(capacity, || None);
+        Self {
+            slots,
+            utf8,
+            tracking,
+            tracked: Vec::new(),
+            len: 0,
+            shift: 64 - capacity.trailing_zeros(),
+        }
+    }
+
+    /// Records the temperature `temp` for the station identified by `key`.
+    ///
+    /// With [`Utf8::Reject`], the name of a new station must be valid UTF-8, otherwise nothing is
+    /// recorded and `false` is returned. This makes every name of the table valid UTF-8 while
+    /// only validating the name of each station once.
+    #[inline]
+    pub(crate) fn add(&mut self, key: Key<'a>, temp: i16) -> bool {
+        let mask = self.slots.len() - 1;
+        let mut index = self.index(key.hash);
+        loop {
+            match &mut self.slots[index] {
+                Some(slot) => {
+                    if slot.key.matches(&key) {
+                        // every station has its entry as soon as anything is tracked
+                        if !self.tracked.is_empty() {
+                            let readings = &slot.readings;
+                            self.tracked[readings.index as usize].add(readings.cnt, temp);
+                        }
+                        slot.readings.add(temp);
+                        return true;
+                    }
+                    index = (index + 1) & mask;
+                }
+                empty => {
+                    if self.utf8 == Utf8::Reject && simdutf8::basic::from_utf8(key.name).is_err() {
+                        return false;
+                    }
+                    let index = self.tracked.len() as u32;
+                    if self.tracking != Tracking::default() {
+                        let mut tracked = Tracked::new(self.tracking);
+                        tracked.add(0, temp);
+                        self.tracked.push(tracked);
+                    }
+                    let readings = Readings {
+                        min: temp,
+                        max: temp,
+                        index,
+                        sum: temp as i64,
+                        cnt: 1,
+                    };
+                    *empty = Some(Slot { key, readings });
+                    self.len += 1;
+                    if self.len * 2 > self.slots.len() {
+                        self.grow();
+                    }
+                    return true;
+                }
+            }
+        }
+    }
+
+    /// Consumes the table, yielding the station names (still quoted) with their sensor
+    pub(crate) fn into_sensors(self) -> impl Iterator<Item = (Cow<'a, str>, Sensor)> {
+        let utf8 = self.utf8;
+        let mut tracked = self.tracked;
+        self.slots.into_iter().flatten().map(move |slot| {
+            let name = match utf8 {
+                // SAFETY: `add` only inserts valid UTF-8 names with `Utf8::Reject`
+                Utf8::Reject => {
+                    Cow::Borrowed(unsafe { std::str::from_utf8_unchecked(slot.key.name) })
+                }
+                Utf8::Lossy => String::from_utf8_lossy(slot.key.name),
+            };
+            let Readings {
+                min,
+                max,
+                index,
+                sum,
+                cnt,
+            } = slot.readings;
+            let Tracked {
+                moments,
+                histogram,
+                sketch,
+            } = tracked
+                .get_mut(index as usize)
+                .map(std::mem::take)
+                .unwrap_or_default();
+            let sensor = Sensor::from_tenths(min, sum, cnt, max, moments, histogram, sketch);
+            (name, sensor)
+        })
+    }
+
+    #[inline]
+    fn index(&self, hash: u64) -> usize {
+        // fibonacci hashing, spreads the low-entropy bits of `hash_name` over the index
+        (hash.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> self.shift) as usize
+    }
+
+    fn grow(&mut self) {
+        let slots = std::mem::take(&mut self.slots);
+        let tracked = std::mem::take(&mut self.tracked);
+        *self = Self::with_capacity(slots.len() * 2, self.utf8, self.tracking);
+        self.tracked = tracked;
+        let mask = self.slots.len() - 1;
+        for slot in slots.into_iter().flatten() {
+            let mut index = self.index(slot.key.hash);
+            while self.slots[index].is_some() {
+                index = (index + 1) & mask;
+            }
+            self.slots[index] = Some(slot);
+            self.len += 1;
+        }
+    }
+}
+
+impl Readings {
+    #[inline]
+    fn add(&mut self, temp: i16) {
+        if temp < self.min {
+            self.min = temp;
+        }
+        if temp > self.max {
+            self.max = temp;
+        }
+        self.sum += temp as i64;
+        self.cnt += 1;
+    }
+}
+
+impl Key<'_> {
+    #[inline]
+    fn matches(&self, other: &Key<'_>) -> bool {
+        self.hash == other.hash
+            && self.inline == other.inline
+            && self.name.len() == other.name.len()
+            && (self.name.len() <= INLINE || self.name == other.name)
+    }
+}
+
+/// A station name along with its hash and first `INLINE` bytes, zero padded
+#[derive(Clone, Copy)]
+pub(crate) struct Key<'a> {
+    name: &'a [u8],
+    inline: [u64; 2],
+    hash: u64,
+}
+
+impl<'a> Key<'a> {
+    /// Builds the key of the name `data[start..end]`.
+    ///
+    /// Looking at the whole `data` allows to load the first `INLINE` bytes with two unaligned
+    /// reads instead of a copy whenever the name is not at the very end of the input.
+    #[inline]
+    pub(crate) fn new(data: &'a [u8], start: usize, end: usize) -> Self {
+        let name = &data[start..end];
+        let inline = match data.get(start..start + INLINE) {
+            Some(bytes) => {
+                // clear the bytes past the end of the name, without branching on its length
+                let low = name.len().min(8) * 8;
+                let high = name.len().clamp(8, INLINE) * 8 - 64;
+                [
+                    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+                        & ((1u128 << low) - 1) as u64,
+                    u64::from_le_bytes(bytes[8..].try_into().unwrap())
+                        & ((1u128 << high) - 1) as u64,
+                ]
+            }
+            None => {
+                let mut bytes = [0; INLINE];
+                let len = name.len().min(INLINE);
+                bytes[..len].copy_from_slice(&name[..len]);
+                [
+                    u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+                    u64::from_le_bytes(bytes[8..].try_into().unwrap()),
+                ]
+            }
+        };
+        Self {
+            name,
+            inline,
+            hash: hash_name(name, inline),
+        }
+    }
+
+    /// The raw station name
+    pub(crate) fn name(&self) -> &'a [u8] {
+        self.name
+    }
+}
+
+/// Hashes a station name 8 bytes at a time (FxHash), starting with its inline words
+#[inline]
+fn hash_name(name: &[u8], inline: [u64; 2]) -> u64 {
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+    let mut hash = (name.len() as u64 ^ inline[0]).wrapping_mul(SEED);
+    hash = (hash.rotate_left(5) ^ inline[1]).wrapping_mul(SEED);
+    if name.len() > INLINE {
+        for word in name[INLINE..].chunks(8) {
+            let mut bytes = [0; 8];
+            bytes[..word.len()].copy_from_slice(word);
+            hash = (hash.rotate_left(5) ^ u64::from_le_bytes(bytes)).wrapping_mul(SEED);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn slots_fit_a_cache_line() {
+        assert_eq!(std::mem::size_of::<Option<Slot<'_>>>(), 64);
+    }
+
+    #[test]
+    fn tracked_statistics_survive_growing() {
+        let names: Vec<String> = (0..3 * INITIAL_CAPACITY).map(|i| format!("s{i}")).collect();
+        let tracking = Tracking {
+            moments: true,
+            histogram: true,
+            sketch: None,
+        };
+        let mut table = StationTable::new(Utf8::Reject, tracking);
+        for temp in [10, 20, 60] {
+            for (i, name) in names.iter().enumerate() {
+                let key = Key::new(name.as_bytes(), 0, name.len());
+                assert!(table.add(key, temp + (i % 7) as i16));
+            }
+        }
+        assert!(table.slots.len() > INITIAL_CAPACITY);
+        let sensors: Vec<_> = table.into_sensors().collect();
+        assert_eq!(sensors.len(), names.len());
+        for (name, sensor) in sensors {
+            let offset = name[1..].parse::<usize>().unwrap() % 7;
+            assert_eq!(sensor.count(), 3);
+            assert_eq!(sensor.median(), Some(2.0 + offset as f64 / 10.0));
+            let variance = sensor.variance().unwrap();
+            assert!((variance - 14.0 / 3.0).abs() < 1e-9, "{name}: {variance}");
+        }
+
+        let mut table = StationTable::new(Utf8::Reject, Tracking::default());
+        assert!(table.add(Key::new(b"a", 0, 1), 10));
+        let (_, sensor) = table.into_sensors().next().unwrap();
+        assert_eq!(
+            (sensor.count(), sensor.variance(), sensor.median()),
+            (1, None, None)
+        );
+    }
+}